@@ -0,0 +1,287 @@
+use std::{fmt, io, path::Path, time::Duration};
+
+use crate::bench::Measurement;
+
+/// Saved result of one backend over one trace
+#[derive(Debug, Clone, PartialEq)]
+pub struct BaselineEntry {
+    pub backend: String,
+    pub trace: String,
+    pub median: Duration,
+    pub stddev: Duration,
+}
+
+/// Results of an earlier run, stored in the csv format written by `report::write_csv`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Baseline {
+    pub entries: Vec<BaselineEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verdict {
+    Faster,
+    Unchanged,
+    Slower,
+}
+
+/// A measurement compared against its baseline entry
+#[derive(Debug, Clone, PartialEq)]
+pub struct Comparison {
+    pub backend: String,
+    pub trace: String,
+    pub baseline: Duration,
+    pub current: Duration,
+    /// Relative change of the median, positive when slower
+    pub change: f64,
+    pub verdict: Verdict,
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let verdict = match self.verdict {
+            Verdict::Faster => "faster",
+            Verdict::Unchanged => "unchanged",
+            Verdict::Slower => "REGRESSION",
+        };
+        write!(
+            f,
+            "{}: {}: {:?} -> {:?} ({:+.1}%) {}",
+            self.backend,
+            self.trace,
+            self.baseline,
+            self.current,
+            self.change * 100.0,
+            verdict
+        )
+    }
+}
+
+impl Baseline {
+    pub fn from_measurements(measurements: &[Measurement]) -> Self {
+        let entries = measurements
+            .iter()
+            .map(|m| {
+                let summary = m.summary();
+                BaselineEntry {
+                    backend: m.backend.clone(),
+                    trace: m.trace.clone(),
+                    median: summary.median,
+                    stddev: summary.stddev,
+                }
+            })
+            .collect();
+        Self { entries }
+    }
+
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse a result csv, only the backend, trace, median and stddev columns are used
+    pub fn parse(csv: &str) -> io::Result<Self> {
+        let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+
+        let mut lines = csv.lines().filter(|l| !l.trim().is_empty());
+        let header = split_csv_line(
+            lines
+                .next()
+                .ok_or_else(|| invalid("empty baseline".into()))?,
+        );
+        let column = |name: &str| {
+            header
+                .iter()
+                .position(|h| h == name)
+                .ok_or_else(|| invalid(format!("baseline has no {} column", name)))
+        };
+        let (backend, trace) = (column("backend")?, column("trace")?);
+        let (median, stddev) = (column("median_ns")?, column("stddev_ns")?);
+
+        let mut entries = vec![];
+        for (i, line) in lines.enumerate() {
+            let fields = split_csv_line(line);
+            let field = |at: usize| {
+                fields
+                    .get(at)
+                    .ok_or_else(|| invalid(format!("baseline row {} is too short", i + 1)))
+            };
+            let nanos = |at: usize| {
+                field(at)?
+                    .parse::<u64>()
+                    .map(Duration::from_nanos)
+                    .map_err(|_| invalid(format!("baseline row {} has a bad duration", i + 1)))
+            };
+
+            entries.push(BaselineEntry {
+                backend: field(backend)?.clone(),
+                trace: field(trace)?.clone(),
+                median: nanos(median)?,
+                stddev: nanos(stddev)?,
+            });
+        }
+
+        Ok(Self { entries })
+    }
+
+    pub fn get(&self, backend: &str, trace: &str) -> Option<&BaselineEntry> {
+        self.entries
+            .iter()
+            .find(|e| e.backend == backend && e.trace == trace)
+    }
+
+    /// Compare medians against the baseline, measurements without an entry are skipped,
+    /// as are those whose baseline median is zero, no relative change can be told from it
+    /// a change is flagged once it exceeds `threshold` (e.g. 0.05 for 5%)
+    /// or twice the relative standard deviation of the baseline, whichever is larger
+    pub fn compare(&self, measurements: &[Measurement], threshold: f64) -> Vec<Comparison> {
+        measurements
+            .iter()
+            .filter_map(|m| {
+                let base = self.get(&m.backend, &m.trace)?;
+                if base.median.is_zero() {
+                    return None;
+                }
+                let current = m.summary().median;
+                let base_secs = base.median.as_secs_f64();
+                let change = current.as_secs_f64() / base_secs - 1.0;
+                let noise = threshold.max(2.0 * base.stddev.as_secs_f64() / base_secs);
+
+                let verdict = if change > noise {
+                    Verdict::Slower
+                } else if change < -noise {
+                    Verdict::Faster
+                } else {
+                    Verdict::Unchanged
+                };
+
+                Some(Comparison {
+                    backend: m.backend.clone(),
+                    trace: m.trace.clone(),
+                    baseline: base.median,
+                    current,
+                    change,
+                    verdict,
+                })
+            })
+            .collect()
+    }
+}
+
+/// Split a csv line, honouring double quoted fields
+fn split_csv_line(line: &str) -> Vec<String> {
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                field.push('"');
+                chars.next();
+            }
+            '"' => quoted = !quoted,
+            ',' if !quoted => fields.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ReplayStats,
+        bench::{
+            Measurement, Run,
+            baseline::{Baseline, Verdict},
+            report::write_csv,
+        },
+    };
+
+    fn measurement(backend: &str, trace: &str, ms: u64) -> Measurement {
+        Measurement {
+            backend: backend.to_string(),
+            trace: trace.to_string(),
+            runs: vec![Run {
+                elapsed: Duration::from_millis(ms),
+                stats: ReplayStats::default(),
+            }],
+        }
+    }
+
+    #[test]
+    fn roundtrips_through_result_csv() {
+        let measurements = vec![
+            measurement("PagedMem(FxHash)", "fib", 100),
+            measurement("odd, \"name\"", "exec_block", 200),
+        ];
+        let mut csv = vec![];
+        write_csv(&mut csv, &measurements).unwrap();
+
+        let baseline = Baseline::parse(std::str::from_utf8(&csv).unwrap()).unwrap();
+        assert_eq!(baseline, Baseline::from_measurements(&measurements));
+    }
+
+    #[test]
+    fn flags_changes_beyond_threshold() {
+        let baseline = Baseline::from_measurements(&[
+            measurement("a", "fib", 100),
+            measurement("b", "fib", 100),
+            measurement("c", "fib", 100),
+        ]);
+
+        let verdicts: Vec<_> = baseline
+            .compare(
+                &[
+                    measurement("a", "fib", 80),
+                    measurement("b", "fib", 103),
+                    measurement("c", "fib", 120),
+                    measurement("d", "fib", 100),
+                ],
+                0.05,
+            )
+            .into_iter()
+            .map(|c| (c.backend, c.verdict))
+            .collect();
+
+        assert_eq!(
+            verdicts,
+            vec![
+                ("a".to_string(), Verdict::Faster),
+                ("b".to_string(), Verdict::Unchanged),
+                ("c".to_string(), Verdict::Slower),
+            ]
+        );
+    }
+
+    #[test]
+    fn noisy_baseline_widens_threshold() {
+        let mut baseline = Baseline::from_measurements(&[measurement("a", "fib", 100)]);
+        baseline.entries[0].stddev = Duration::from_millis(15);
+
+        let comparison = baseline.compare(&[measurement("a", "fib", 120)], 0.05);
+        assert_eq!(comparison[0].verdict, Verdict::Unchanged);
+
+        let comparison = baseline.compare(&[measurement("a", "fib", 131)], 0.05);
+        assert_eq!(comparison[0].verdict, Verdict::Slower);
+    }
+
+    #[test]
+    fn zero_baseline_is_not_compared() {
+        let baseline = Baseline::from_measurements(&[
+            measurement("a", "fib", 0),
+            measurement("b", "fib", 100),
+        ]);
+
+        let comparison = baseline.compare(
+            &[measurement("a", "fib", 100), measurement("b", "fib", 100)],
+            0.05,
+        );
+        assert_eq!(comparison.len(), 1);
+        assert_eq!(comparison[0].backend, "b");
+        assert!(comparison[0].change.is_finite());
+    }
+}
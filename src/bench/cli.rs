@@ -0,0 +1,214 @@
+use std::path::{Path, PathBuf};
+
+pub const USAGE: &str = "\
+usage: fast-mem [options] <trace>...
+
+options:
+  -b, --backend <name>        backend to run, by key or emulator name (repeatable, default: all)
+  -n, --reps <n>              measured repetitions per backend and trace (default: 1)
+  -w, --warmup <n>            untimed warmup runs per backend and trace (default: 0)
+  -f, --format <fmt>          output format: text, json or csv (default: text)
+  -o, --output <file>         also write the results to a file, as csv if it ends in .csv, json otherwise
+      --save-baseline <file>  save the results as a baseline for later runs
+      --baseline <file>       compare against a saved baseline, exits with status 3 on a regression
+      --threshold <pct>       smallest change in percent reported against the baseline (default: 5)
+      --verify                check every trace against its header checksum before replaying it
+  -l, --list                  list the available backends and exit
+  -h, --help                  print this message and exit";
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub traces: Vec<PathBuf>,
+    /// Backend keys or names, empty selects every backend
+    pub backends: Vec<String>,
+    pub reps: usize,
+    pub warmup: usize,
+    pub format: OutputFormat,
+    /// Machine readable result file
+    pub output: Option<PathBuf>,
+    pub save_baseline: Option<PathBuf>,
+    pub baseline: Option<PathBuf>,
+    /// Noise threshold for baseline comparison, as a fraction
+    pub threshold: f64,
+    /// Check trace checksums before the runs, see `verify_trace`
+    pub verify: bool,
+}
+
+impl OutputFormat {
+    /// Machine readable format for a result file, picked from its extension
+    pub fn for_file(path: &Path) -> Self {
+        match path.extension() {
+            Some(ext) if ext == "csv" => OutputFormat::Csv,
+            _ => OutputFormat::Json,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            traces: vec![],
+            backends: vec![],
+            reps: 1,
+            warmup: 0,
+            format: OutputFormat::default(),
+            output: None,
+            save_baseline: None,
+            baseline: None,
+            threshold: 0.05,
+            verify: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Command {
+    Run(Options),
+    List,
+    Help,
+}
+
+/// Parse the command line, without the program name
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Command, String> {
+    let mut options = Options::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        let mut value = |flag: &str| {
+            args.next()
+                .ok_or_else(|| format!("missing value for {}", flag))
+        };
+
+        match arg.as_str() {
+            "-h" | "--help" => return Ok(Command::Help),
+            "-l" | "--list" => return Ok(Command::List),
+            "-b" | "--backend" => options.backends.push(value(&arg)?),
+            "-n" | "--reps" => options.reps = parse_count(&arg, &value(&arg)?)?,
+            "-w" | "--warmup" => options.warmup = parse_count(&arg, &value(&arg)?)?,
+            "-f" | "--format" => {
+                options.format = match value(&arg)?.as_str() {
+                    "text" => OutputFormat::Text,
+                    "json" => OutputFormat::Json,
+                    "csv" => OutputFormat::Csv,
+                    other => return Err(format!("unknown output format: {}", other)),
+                }
+            }
+            "-o" | "--output" => options.output = Some(PathBuf::from(value(&arg)?)),
+            "--save-baseline" => options.save_baseline = Some(PathBuf::from(value(&arg)?)),
+            "--baseline" => options.baseline = Some(PathBuf::from(value(&arg)?)),
+            "--verify" => options.verify = true,
+            "--threshold" => {
+                let value = value(&arg)?;
+                let percent: f64 = value
+                    .parse()
+                    .ok()
+                    .filter(|p: &f64| p.is_finite() && *p >= 0.0)
+                    .ok_or_else(|| format!("invalid value for {}: {}", arg, value))?;
+                options.threshold = percent / 100.0;
+            }
+            flag if flag.starts_with('-') && flag.len() > 1 => {
+                return Err(format!("unknown option: {}", flag));
+            }
+            _ => options.traces.push(PathBuf::from(arg)),
+        }
+    }
+
+    if options.traces.is_empty() {
+        return Err("no trace files given".to_string());
+    }
+    if options.reps == 0 {
+        return Err("--reps must be at least 1".to_string());
+    }
+
+    Ok(Command::Run(options))
+}
+
+fn parse_count(flag: &str, value: &str) -> Result<usize, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {}: {}", flag, value))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::bench::cli::{Command, Options, OutputFormat, parse_args};
+
+    fn parse(args: &[&str]) -> Result<Command, String> {
+        parse_args(args.iter().map(|a| a.to_string()))
+    }
+
+    #[test]
+    fn parses_full_command_line() {
+        let command = parse(&[
+            "-b",
+            "paged:fx",
+            "--backend",
+            "noop",
+            "-n",
+            "5",
+            "--warmup",
+            "2",
+            "-f",
+            "csv",
+            "-o",
+            "out.json",
+            "--verify",
+            "fib.bin",
+            "exec.bin",
+        ]);
+
+        assert_eq!(
+            command,
+            Ok(Command::Run(Options {
+                traces: vec![PathBuf::from("fib.bin"), PathBuf::from("exec.bin")],
+                backends: vec!["paged:fx".to_string(), "noop".to_string()],
+                reps: 5,
+                warmup: 2,
+                format: OutputFormat::Csv,
+                output: Some(PathBuf::from("out.json")),
+                verify: true,
+                ..Default::default()
+            }))
+        );
+    }
+
+    #[test]
+    fn parses_baseline_options() {
+        let Ok(Command::Run(options)) = parse(&[
+            "--baseline",
+            "base.csv",
+            "--save-baseline",
+            "next.csv",
+            "--threshold",
+            "2.5",
+            "fib.bin",
+        ]) else {
+            panic!("expected a run command");
+        };
+
+        assert_eq!(options.baseline, Some(PathBuf::from("base.csv")));
+        assert_eq!(options.save_baseline, Some(PathBuf::from("next.csv")));
+        assert!((options.threshold - 0.025).abs() < 1e-12);
+    }
+
+    #[test]
+    fn rejects_bad_arguments() {
+        assert!(parse(&[]).is_err());
+        assert!(parse(&["-n", "0", "fib.bin"]).is_err());
+        assert!(parse(&["-n", "fib.bin"]).is_err());
+        assert!(parse(&["--format", "xml", "fib.bin"]).is_err());
+        assert!(parse(&["--frobnicate", "fib.bin"]).is_err());
+        assert!(parse(&["--threshold", "-1", "fib.bin"]).is_err());
+        assert_eq!(parse(&["fib.bin", "--list"]), Ok(Command::List));
+    }
+}
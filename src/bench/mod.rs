@@ -0,0 +1,94 @@
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    MemoryEmulator, ReplayStats, bench::registry::Backend, bench::stats::Summary,
+    replay_mem_operations, replay_reader::ReplayError,
+};
+
+pub mod baseline;
+pub mod cli;
+pub mod registry;
+pub mod report;
+pub mod stats;
+
+/// A single timed replay of a trace
+#[derive(Debug, Clone, Copy)]
+pub struct Run {
+    pub elapsed: Duration,
+    pub stats: ReplayStats,
+}
+
+/// Replay a trace against a fresh emulator and time it
+/// construction of the emulator is not timed, `finish` is called after timing
+pub fn time_replay<M: MemoryEmulator + Default>(path: &Path) -> Result<Run, ReplayError> {
+    time_replay_with(M::default(), path)
+}
+
+/// Replay a trace against an already constructed emulator and time it
+pub fn time_replay_with<M: MemoryEmulator>(emulator: M, path: &Path) -> Result<Run, ReplayError> {
+    time_replay_then(emulator, path, |_| {})
+}
+
+/// Like `time_replay_with`, but `then` runs on the emulator within the timing
+/// e.g. to include committing to the final memory in the measurement
+pub fn time_replay_then<M: MemoryEmulator>(
+    mut emulator: M,
+    path: &Path,
+    then: impl FnOnce(&mut M),
+) -> Result<Run, ReplayError> {
+    let start = Instant::now();
+    let stats = replay_mem_operations(path, &mut emulator)?;
+    then(&mut emulator);
+    let elapsed = start.elapsed();
+
+    emulator.finish();
+    Ok(Run { elapsed, stats })
+}
+
+/// Every measured run of one backend over one trace
+#[derive(Debug, Clone)]
+pub struct Measurement {
+    /// `MemoryEmulator::name` of the backend
+    pub backend: String,
+    /// Trace label, see `report::trace_label`
+    pub trace: String,
+    pub runs: Vec<Run>,
+}
+
+impl Measurement {
+    pub fn summary(&self) -> Summary {
+        Summary::of(&self.runs)
+    }
+}
+
+/// Replay a trace `warmup` times untimed, then `reps` times measured
+/// `on_run` sees every measured run as it completes
+pub fn run_benchmark<F: FnMut(&Run)>(
+    backend: &Backend,
+    trace: &Path,
+    warmup: usize,
+    reps: usize,
+    mut on_run: F,
+) -> Result<Measurement, ReplayError> {
+    assert!(reps > 0, "at least one measured run is needed");
+
+    for _ in 0..warmup {
+        backend.run(trace)?;
+    }
+
+    let mut runs = Vec::with_capacity(reps);
+    for _ in 0..reps {
+        let run = backend.run(trace)?;
+        on_run(&run);
+        runs.push(run);
+    }
+
+    Ok(Measurement {
+        backend: backend.name.to_string(),
+        trace: report::trace_label(trace),
+        runs,
+    })
+}
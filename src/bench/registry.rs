@@ -0,0 +1,213 @@
+use std::path::Path;
+
+use crate::{
+    MemoryEmulator,
+    bench::{Run, time_replay, time_replay_then, time_replay_with},
+    emulators::{
+        merkle::MerkleMemory,
+        mmap_flat::MmapFlatMemory,
+        noop::NoopMem,
+        page_table::PageTableMemory,
+        paged::{
+            PagedMemory2M, PagedMemory64K, PagedMemoryAHash, PagedMemoryCowFxHash,
+            PagedMemoryDefault, PagedMemoryFxHash, PagedMemoryNoHashU64,
+        },
+        paged_last_cache::{
+            PagedMemoryCacheLast2M, PagedMemoryCacheLast64K, PagedMemoryCacheLastAHash,
+            PagedMemoryCacheLastCowFxHash, PagedMemoryCacheLastDefault, PagedMemoryCacheLastFxHash,
+            PagedMemoryCacheLastNoHashU64,
+        },
+        paged_split_cache::{
+            PagedMemorySplitCacheAHash, PagedMemorySplitCacheDefault, PagedMemorySplitCacheFxHash,
+            PagedMemorySplitCacheNoHashU64,
+        },
+        paged_tlb::{
+            PagedMemoryTlb8x8, PagedMemoryTlb16x4, PagedMemoryTlb64x4, PagedMemoryTlb256x8,
+        },
+        radix::RadixMemory,
+        region::RegionMemory,
+        timestamped::TimestampedMemory,
+    },
+    named_hasher::FxHash,
+    replay_reader::ReplayError,
+};
+
+/// A benchmarkable memory emulator
+pub struct Backend {
+    /// Short name used on the command line, e.g. `paged:fx`
+    pub key: &'static str,
+    /// `MemoryEmulator::name` of the backend
+    /// spelled out rather than asked of an instance, some backends are costly to build
+    pub name: &'static str,
+    run: fn(&Path) -> Result<Run, ReplayError>,
+    /// Builds the emulator to check `name` against
+    #[cfg(test)]
+    emulator_name: Option<fn() -> String>,
+}
+
+impl Backend {
+    fn new<M: MemoryEmulator + Default>(key: &'static str, name: &'static str) -> Self {
+        Self {
+            key,
+            name,
+            run: time_replay::<M>,
+            #[cfg(test)]
+            emulator_name: Some(|| M::default().name()),
+        }
+    }
+
+    /// A backend whose emulator is built by `run` itself, e.g. from the trace
+    fn custom(
+        key: &'static str,
+        name: &'static str,
+        run: fn(&Path) -> Result<Run, ReplayError>,
+    ) -> Self {
+        Self {
+            key,
+            name,
+            run,
+            #[cfg(test)]
+            emulator_name: None,
+        }
+    }
+
+    /// Time one replay of a trace against a fresh instance of the backend
+    pub fn run(&self, path: &Path) -> Result<Run, ReplayError> {
+        (self.run)(path)
+    }
+}
+
+/// Every backend the benchmark binary can run, keyed by `MemoryEmulator::name`
+pub struct Registry {
+    backends: Vec<Backend>,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self {
+            backends: vec![
+                Backend::new::<NoopMem>("noop", "NoopMem"),
+                Backend::new::<PagedMemoryDefault>("paged:sip", "PagedMem(SipHash)"),
+                Backend::new::<PagedMemoryAHash>("paged:ahash", "PagedMem(AHash)"),
+                Backend::new::<PagedMemoryFxHash>("paged:fx", "PagedMem(FxHash)"),
+                Backend::new::<PagedMemoryNoHashU64>("paged:nohash", "PagedMem(NoHashU64)"),
+                Backend::new::<PagedMemory64K<FxHash>>("paged:fx:64k", "PagedMem64K(FxHash)"),
+                Backend::new::<PagedMemory2M<FxHash>>("paged:fx:2m", "PagedMem2M(FxHash)"),
+                Backend::new::<PagedMemoryCowFxHash>("paged:fx:cow", "PagedMemCow(FxHash)"),
+                Backend::new::<PagedMemoryCacheLastDefault>(
+                    "cache-last:sip",
+                    "PagedMemCacheLast(SipHash)",
+                ),
+                Backend::new::<PagedMemoryCacheLastAHash>(
+                    "cache-last:ahash",
+                    "PagedMemCacheLast(AHash)",
+                ),
+                Backend::new::<PagedMemoryCacheLastFxHash>(
+                    "cache-last:fx",
+                    "PagedMemCacheLast(FxHash)",
+                ),
+                Backend::new::<PagedMemoryCacheLastNoHashU64>(
+                    "cache-last:nohash",
+                    "PagedMemCacheLast(NoHashU64)",
+                ),
+                Backend::new::<PagedMemoryCacheLast64K<FxHash>>(
+                    "cache-last:fx:64k",
+                    "PagedMemCacheLast64K(FxHash)",
+                ),
+                Backend::new::<PagedMemoryCacheLast2M<FxHash>>(
+                    "cache-last:fx:2m",
+                    "PagedMemCacheLast2M(FxHash)",
+                ),
+                Backend::new::<PagedMemoryCacheLastCowFxHash>(
+                    "cache-last:fx:cow",
+                    "PagedMemCacheLastCow(FxHash)",
+                ),
+                Backend::new::<PagedMemorySplitCacheDefault>(
+                    "split-cache:sip",
+                    "PagedMemSplitCache(SipHash)",
+                ),
+                Backend::new::<PagedMemorySplitCacheAHash>(
+                    "split-cache:ahash",
+                    "PagedMemSplitCache(AHash)",
+                ),
+                Backend::new::<PagedMemorySplitCacheFxHash>(
+                    "split-cache:fx",
+                    "PagedMemSplitCache(FxHash)",
+                ),
+                Backend::new::<PagedMemorySplitCacheNoHashU64>(
+                    "split-cache:nohash",
+                    "PagedMemSplitCache(NoHashU64)",
+                ),
+                Backend::new::<PagedMemoryTlb8x8>("tlb:8x8", "PagedMemTlb8x8(FxHash)"),
+                Backend::new::<PagedMemoryTlb16x4>("tlb:16x4", "PagedMemTlb16x4(FxHash)"),
+                Backend::new::<PagedMemoryTlb64x4>("tlb:64x4", "PagedMemTlb64x4(FxHash)"),
+                Backend::new::<PagedMemoryTlb256x8>("tlb:256x8", "PagedMemTlb256x8(FxHash)"),
+                Backend::new::<PageTableMemory>("page-table", "PageTableMem"),
+                Backend::new::<RadixMemory>("radix", "RadixMem"),
+                Backend::new::<RegionMemory>("region", "RegionMem"),
+                Backend::custom("region:learned", "RegionMem(learned)", |path| {
+                    time_replay_with(RegionMemory::for_trace(path)?, path)
+                }),
+                Backend::new::<MmapFlatMemory>("mmap-flat", "MmapFlat"),
+                Backend::custom("merkle", "MerkleMem(SHA-256)", |path| {
+                    time_replay_then(MerkleMemory::default(), path, |mem| {
+                        mem.commit_root();
+                    })
+                }),
+                Backend::custom("timestamped", "Timestamped(PagedMem(FxHash))", |path| {
+                    let sink = |access: &_| {
+                        std::hint::black_box(access);
+                    };
+                    time_replay_with(
+                        TimestampedMemory::new(PagedMemoryFxHash::default(), sink),
+                        path,
+                    )
+                }),
+            ],
+        }
+    }
+}
+
+impl Registry {
+    /// Look a backend up by its `MemoryEmulator::name` or its short key
+    pub fn get(&self, name: &str) -> Option<&Backend> {
+        self.backends
+            .iter()
+            .find(|b| b.name == name || b.key == name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Backend> {
+        self.backends.iter()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::bench::registry::Registry;
+
+    #[test]
+    fn keys_and_names_are_unique() {
+        let registry = Registry::default();
+        let mut seen = HashSet::new();
+        for backend in registry.iter() {
+            assert!(seen.insert(backend.key));
+            assert!(seen.insert(backend.name));
+        }
+
+        let by_key = registry.get("cache-last:fx").unwrap();
+        let by_name = registry.get("PagedMemCacheLast(FxHash)").unwrap();
+        assert_eq!(by_key.key, by_name.key);
+        assert!(registry.get("paged:md5").is_none());
+    }
+
+    #[test]
+    fn names_match_the_emulators() {
+        for backend in Registry::default().iter() {
+            if let Some(emulator_name) = backend.emulator_name {
+                assert_eq!(backend.name, emulator_name());
+            }
+        }
+    }
+}
@@ -0,0 +1,169 @@
+use std::{
+    io::{self, Write},
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::bench::Measurement;
+
+/// Short label for a trace, the file name without its extension
+pub fn trace_label(path: &Path) -> String {
+    path.file_stem()
+        .unwrap_or(path.as_os_str())
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// Write a human readable summary of one measurement
+pub fn write_text_summary<W: Write>(out: &mut W, m: &Measurement) -> io::Result<()> {
+    let s = m.summary();
+    writeln!(
+        out,
+        "min {:?}, median {:?}, mean {:?} ± {:?} ({:.1}%) over {} runs, {:.0} ops/s",
+        s.min,
+        s.median,
+        s.mean,
+        s.stddev,
+        s.relative_stddev() * 100.0,
+        s.runs,
+        s.ops_per_sec
+    )
+}
+
+/// Write one summary line per measurement
+pub fn write_csv<W: Write>(out: &mut W, measurements: &[Measurement]) -> io::Result<()> {
+    writeln!(
+        out,
+        "backend,trace,runs,records,min_ns,median_ns,mean_ns,stddev_ns,ops_per_sec"
+    )?;
+    for m in measurements {
+        let s = m.summary();
+        writeln!(
+            out,
+            "{},{},{},{},{},{},{},{},{:.0}",
+            csv_field(&m.backend),
+            csv_field(&m.trace),
+            s.runs,
+            s.records,
+            s.min.as_nanos(),
+            s.median.as_nanos(),
+            s.mean.as_nanos(),
+            s.stddev.as_nanos(),
+            s.ops_per_sec
+        )?;
+    }
+    Ok(())
+}
+
+/// Write every measurement, its summary and raw run times as JSON
+pub fn write_json<W: Write>(out: &mut W, measurements: &[Measurement]) -> io::Result<()> {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    writeln!(out, "{{")?;
+    writeln!(out, "  \"timestamp\": {},", timestamp)?;
+    writeln!(out, "  \"results\": [")?;
+    for (i, m) in measurements.iter().enumerate() {
+        let s = m.summary();
+        let nanos: Vec<String> = m
+            .runs
+            .iter()
+            .map(|r| r.elapsed.as_nanos().to_string())
+            .collect();
+
+        write!(
+            out,
+            "    {{\"backend\": {}, \"trace\": {}, \"runs\": {}, \"records\": {}, \
+             \"min_ns\": {}, \"median_ns\": {}, \"mean_ns\": {}, \"stddev_ns\": {}, \
+             \"ops_per_sec\": {:.0}, \"nanos\": [{}]}}",
+            json_string(&m.backend),
+            json_string(&m.trace),
+            s.runs,
+            s.records,
+            s.min.as_nanos(),
+            s.median.as_nanos(),
+            s.mean.as_nanos(),
+            s.stddev.as_nanos(),
+            s.ops_per_sec,
+            nanos.join(", ")
+        )?;
+        writeln!(out, "{}", if i + 1 < measurements.len() { "," } else { "" })?;
+    }
+    writeln!(out, "  ]")?;
+    writeln!(out, "}}")
+}
+
+pub(crate) fn json_string(s: &str) -> String {
+    let mut out = String::with_capacity(s.len() + 2);
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+pub(crate) fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ReplayStats,
+        bench::{
+            Measurement, Run,
+            report::{write_csv, write_json},
+        },
+    };
+
+    fn measurement() -> Measurement {
+        let run = |ms| Run {
+            elapsed: Duration::from_millis(ms),
+            stats: ReplayStats {
+                records: 3,
+                loads: 2,
+                stores: 1,
+            },
+        };
+        Measurement {
+            backend: "PagedMem(FxHash)".to_string(),
+            trace: "fib".to_string(),
+            runs: vec![run(1), run(3)],
+        }
+    }
+
+    #[test]
+    fn writes_csv_and_json() {
+        let mut csv = vec![];
+        write_csv(&mut csv, &[measurement()]).unwrap();
+        assert_eq!(
+            String::from_utf8(csv).unwrap(),
+            "backend,trace,runs,records,min_ns,median_ns,mean_ns,stddev_ns,ops_per_sec\n\
+             PagedMem(FxHash),fib,2,3,1000000,2000000,2000000,1414214,1500\n"
+        );
+
+        let mut json = vec![];
+        write_json(&mut json, &[measurement()]).unwrap();
+        let json = String::from_utf8(json).unwrap();
+        assert!(json.contains("\"timestamp\": "));
+        assert!(json.contains(
+            "{\"backend\": \"PagedMem(FxHash)\", \"trace\": \"fib\", \"runs\": 2, \"records\": 3, \
+             \"min_ns\": 1000000, \"median_ns\": 2000000, \"mean_ns\": 2000000, \
+             \"stddev_ns\": 1414214, \"ops_per_sec\": 1500, \"nanos\": [1000000, 3000000]}\n"
+        ));
+    }
+}
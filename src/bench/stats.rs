@@ -0,0 +1,111 @@
+use std::time::Duration;
+
+use crate::bench::Run;
+
+/// Summary statistics over the measured runs of one backend and trace
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Summary {
+    pub runs: usize,
+    /// Records replayed per run
+    pub records: u64,
+    pub min: Duration,
+    pub median: Duration,
+    pub mean: Duration,
+    /// Sample standard deviation, zero for a single run
+    pub stddev: Duration,
+    /// Records replayed per second at the median run time
+    pub ops_per_sec: f64,
+}
+
+impl Summary {
+    /// Summarise a non empty set of runs
+    pub fn of(runs: &[Run]) -> Self {
+        assert!(!runs.is_empty(), "no runs to summarise");
+
+        let mut secs: Vec<f64> = runs.iter().map(|r| r.elapsed.as_secs_f64()).collect();
+        secs.sort_by(f64::total_cmp);
+
+        let n = secs.len();
+        let median = if n % 2 == 1 {
+            secs[n / 2]
+        } else {
+            (secs[n / 2 - 1] + secs[n / 2]) / 2.0
+        };
+        let mean = secs.iter().sum::<f64>() / n as f64;
+        let variance = if n > 1 {
+            secs.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (n - 1) as f64
+        } else {
+            0.0
+        };
+
+        let records = runs[0].stats.records;
+        let ops_per_sec = if median > 0.0 {
+            records as f64 / median
+        } else {
+            0.0
+        };
+
+        Self {
+            runs: n,
+            records,
+            min: Duration::from_secs_f64(secs[0]),
+            median: Duration::from_secs_f64(median),
+            mean: Duration::from_secs_f64(mean),
+            stddev: Duration::from_secs_f64(variance.sqrt()),
+            ops_per_sec,
+        }
+    }
+
+    /// Standard deviation relative to the mean
+    pub fn relative_stddev(&self) -> f64 {
+        let mean = self.mean.as_secs_f64();
+        if mean > 0.0 {
+            self.stddev.as_secs_f64() / mean
+        } else {
+            0.0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use crate::{
+        ReplayStats,
+        bench::{Run, stats::Summary},
+    };
+
+    fn runs(millis: &[u64]) -> Vec<Run> {
+        millis
+            .iter()
+            .map(|&ms| Run {
+                elapsed: Duration::from_millis(ms),
+                stats: ReplayStats {
+                    records: 1000,
+                    ..Default::default()
+                },
+            })
+            .collect()
+    }
+
+    fn approx(d: Duration, ms: f64) -> bool {
+        (d.as_secs_f64() * 1e3 - ms).abs() < 1e-6
+    }
+
+    #[test]
+    fn summarises_runs() {
+        let summary = Summary::of(&runs(&[40, 10, 30, 20]));
+        assert_eq!(summary.runs, 4);
+        assert!(approx(summary.min, 10.0));
+        assert!(approx(summary.median, 25.0));
+        assert!(approx(summary.mean, 25.0));
+        // sample variance of 10, 20, 30, 40 ms is 166.67 ms^2
+        assert!(approx(summary.stddev, 166.666_666_666_666_66f64.sqrt()));
+        assert!((summary.ops_per_sec - 40_000.0).abs() < 1e-6);
+
+        let single = Summary::of(&runs(&[7]));
+        assert_eq!(single.stddev, Duration::ZERO);
+        assert!(approx(single.median, 7.0));
+    }
+}
@@ -0,0 +1,183 @@
+use std::{collections::VecDeque, fmt, path::Path};
+
+use crate::{
+    MemoryEmulator, ReplayStats,
+    replay_reader::{MemOp, ReplayError, ReplayReader, map_trace},
+};
+
+/// Number of operations kept before a divergence by `replay_differential`
+pub const DEFAULT_HISTORY: usize = 16;
+
+/// The first load on which two emulators returned different values
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Divergence {
+    /// Zero based index of the diverging record
+    pub record: u64,
+    pub op: MemOp,
+    /// `MemoryEmulator::name` of both emulators
+    pub names: (String, String),
+    /// Values loaded by each emulator
+    pub values: (u64, u64),
+    /// Operations leading up to the divergence, oldest first
+    pub history: Vec<MemOp>,
+}
+
+#[derive(Debug)]
+pub enum DifferentialError {
+    Replay(ReplayError),
+    Diverged(Box<Divergence>),
+}
+
+impl From<ReplayError> for DifferentialError {
+    fn from(err: ReplayError) -> Self {
+        DifferentialError::Replay(err)
+    }
+}
+
+impl fmt::Display for DifferentialError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DifferentialError::Replay(e) => write!(f, "{}", e),
+            DifferentialError::Diverged(d) => {
+                writeln!(
+                    f,
+                    "divergence at record {}: {}-byte load from 0x{:x}",
+                    d.record,
+                    d.op.width.bytes(),
+                    d.op.addr
+                )?;
+                writeln!(f, "  {}: 0x{:x}", d.names.0, d.values.0)?;
+                writeln!(f, "  {}: 0x{:x}", d.names.1, d.values.1)?;
+                write!(f, "last {} operations:", d.history.len())?;
+                let first = d.record - d.history.len() as u64;
+                for (i, op) in d.history.iter().enumerate() {
+                    write!(
+                        f,
+                        "\n  #{} {:?} {}-byte 0x{:x} = 0x{:x}",
+                        first + i as u64,
+                        op.kind,
+                        op.width.bytes(),
+                        op.addr,
+                        op.value
+                    )?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl std::error::Error for DifferentialError {}
+
+/// Replay a trace against two emulators in lockstep, comparing every load
+/// stops at the first divergence and reports the last `DEFAULT_HISTORY` operations
+pub fn replay_differential<P: AsRef<Path>, A: MemoryEmulator, B: MemoryEmulator>(
+    file_path: P,
+    a: &mut A,
+    b: &mut B,
+) -> Result<ReplayStats, DifferentialError> {
+    replay_differential_with_history(file_path, a, b, DEFAULT_HISTORY)
+}
+
+/// `replay_differential` keeping the last `history` operations for the report
+pub fn replay_differential_with_history<P: AsRef<Path>, A: MemoryEmulator, B: MemoryEmulator>(
+    file_path: P,
+    a: &mut A,
+    b: &mut B,
+    history: usize,
+) -> Result<ReplayStats, DifferentialError> {
+    let mmap = map_trace(file_path).map_err(ReplayError::io)?;
+    let mut recent = VecDeque::with_capacity(history);
+    let mut stats = ReplayStats::default();
+
+    for op in ReplayReader::from_slice(&mmap) {
+        let op = op?;
+        let value_a = op.apply(a);
+        let value_b = op.apply(b);
+
+        if let (Some(value_a), Some(value_b)) = (value_a, value_b)
+            && value_a != value_b
+        {
+            return Err(DifferentialError::Diverged(Box::new(Divergence {
+                record: stats.records,
+                op,
+                names: (a.name(), b.name()),
+                values: (value_a, value_b),
+                history: recent.into(),
+            })));
+        }
+
+        if history > 0 {
+            if recent.len() == history {
+                recent.pop_front();
+            }
+            recent.push_back(op);
+        }
+        stats.record(op.kind);
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        differential::{DifferentialError, replay_differential_with_history},
+        emulators::{paged::PagedMemoryFxHash, paged_last_cache::PagedMemoryCacheLastFxHash},
+        replay_reader::OpKind,
+        test_util::FaultyMemory,
+    };
+
+    fn write_trace(name: &str) -> std::path::PathBuf {
+        let path =
+            std::env::temp_dir().join(format!("fast-mem-{}-{}.bin", std::process::id(), name));
+        let mut trace = vec![];
+        for addr in (0x7000..0x9000u64).step_by(0x400) {
+            trace.extend_from_slice(&[1, 8]);
+            trace.extend_from_slice(&addr.to_le_bytes());
+            trace.extend_from_slice(&(addr * 3).to_le_bytes());
+            trace.extend_from_slice(&[2, 8]);
+            trace.extend_from_slice(&addr.to_le_bytes());
+        }
+        std::fs::write(&path, trace).unwrap();
+        path
+    }
+
+    #[test]
+    fn matching_backends_agree() {
+        let path = write_trace("diff-agree");
+        let stats = replay_differential_with_history(
+            &path,
+            &mut PagedMemoryFxHash::default(),
+            &mut PagedMemoryCacheLastFxHash::default(),
+            4,
+        )
+        .unwrap();
+        assert_eq!(stats.records, 16);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn reports_first_divergence_with_history() {
+        let path = write_trace("diff-diverge");
+        let err = replay_differential_with_history(
+            &path,
+            &mut PagedMemoryFxHash::default(),
+            &mut FaultyMemory::drop_stores(0x8000..u64::MAX),
+            3,
+        )
+        .unwrap_err();
+
+        let DifferentialError::Diverged(d) = err else {
+            panic!("expected a divergence");
+        };
+        // 0x7000..0x8000 takes 4 store/load pairs, then the store to 0x8000 is dropped
+        assert_eq!(d.record, 9);
+        assert_eq!(d.op.addr, 0x8000);
+        assert_eq!(d.values, (0x8000 * 3, 0));
+        assert_eq!(d.history.len(), 3);
+        assert_eq!(d.history[2].kind, OpKind::Store);
+        assert_eq!(d.history[2].addr, 0x8000);
+        std::fs::remove_file(path).unwrap();
+    }
+}
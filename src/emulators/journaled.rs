@@ -0,0 +1,270 @@
+use crate::{
+    MemoryEmulator,
+    replay_reader::{MemOp, OpKind, Width},
+};
+
+/// What a store overwrote
+enum Undo {
+    /// A store of the old value, replayed to undo a `store_u*`
+    Word(MemOp),
+    /// The old contents of a `store_bytes` or `fill` range
+    Bytes { addr: u64, old: Box<[u8]> },
+}
+
+/// Memory wrapper with transactions
+/// inside a transaction every store first records the bytes it overwrites,
+/// so `rollback` undoes the writes without copying the rest of memory
+/// transactions nest, outside of one stores go straight to the inner memory
+/// the old bytes are read with the inner memory's own loads, so a journaled store
+/// has the side effects of a load first, e.g. page allocation or `cache_stats` counts
+pub struct JournaledMemory<M: MemoryEmulator> {
+    inner: M,
+    journal: Vec<Undo>,
+    /// Journal length at every open `begin`, innermost last
+    checkpoints: Vec<usize>,
+}
+
+impl<M: MemoryEmulator + Default> Default for JournaledMemory<M> {
+    fn default() -> Self {
+        Self::new(M::default())
+    }
+}
+
+impl<M: MemoryEmulator> JournaledMemory<M> {
+    pub fn new(inner: M) -> Self {
+        Self {
+            inner,
+            journal: vec![],
+            checkpoints: vec![],
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Give the inner memory back, keeping every write made so far
+    pub fn into_inner(self) -> M {
+        self.inner
+    }
+
+    /// Number of open transactions
+    pub fn depth(&self) -> usize {
+        self.checkpoints.len()
+    }
+
+    /// Number of stores recorded by the open transactions
+    pub fn journal_len(&self) -> usize {
+        self.journal.len()
+    }
+
+    /// Open a transaction, nested in the current one if there is one
+    pub fn begin(&mut self) {
+        self.checkpoints.push(self.journal.len());
+    }
+
+    /// Close the innermost transaction, keeping its writes
+    /// they are only final once the outermost transaction commits,
+    /// until then rolling back an enclosing transaction still undoes them
+    pub fn commit(&mut self) {
+        self.checkpoints
+            .pop()
+            .expect("commit without a matching begin");
+        if self.checkpoints.is_empty() {
+            self.journal.clear();
+        }
+    }
+
+    /// Close the innermost transaction, undoing its writes
+    pub fn rollback(&mut self) {
+        let checkpoint = self
+            .checkpoints
+            .pop()
+            .expect("rollback without a matching begin");
+
+        // newest first, so bytes written twice end up with their oldest value
+        for undo in self.journal.drain(checkpoint..).rev() {
+            match undo {
+                Undo::Word(op) => {
+                    op.apply(&mut self.inner);
+                }
+                Undo::Bytes { addr, old } => self.inner.store_bytes(addr, &old),
+            }
+        }
+    }
+
+    #[inline]
+    fn in_transaction(&self) -> bool {
+        !self.checkpoints.is_empty()
+    }
+
+    /// Record the value a store of `width` at `addr` overwrites
+    /// reads it with a load of the inner memory, side effects included
+    #[inline]
+    fn record_word(&mut self, addr: u64, width: Width) {
+        let old = MemOp {
+            kind: OpKind::Load,
+            width,
+            addr,
+            value: 0,
+        }
+        .apply(&mut self.inner)
+        .unwrap();
+
+        self.journal.push(Undo::Word(MemOp {
+            kind: OpKind::Store,
+            width,
+            addr,
+            value: old,
+        }));
+    }
+
+    /// Record the bytes a bulk store of `len` bytes at `addr` overwrites
+    /// reads them with `load_bytes` of the inner memory, side effects included
+    fn record_bytes(&mut self, addr: u64, len: usize) {
+        let mut old = vec![0; len].into_boxed_slice();
+        self.inner.load_bytes(addr, &mut old);
+        self.journal.push(Undo::Bytes { addr, old });
+    }
+}
+
+impl<M: MemoryEmulator> MemoryEmulator for JournaledMemory<M> {
+    fn name(&self) -> String {
+        format!("Journaled({})", self.inner.name())
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        self.inner.load_u64(addr)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        self.inner.load_u32(addr)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        self.inner.load_u16(addr)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.inner.load_u8(addr)
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        if self.in_transaction() {
+            self.record_word(addr, Width::U64);
+        }
+        self.inner.store_u64(addr, value);
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        if self.in_transaction() {
+            self.record_word(addr, Width::U32);
+        }
+        self.inner.store_u32(addr, value);
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        if self.in_transaction() {
+            self.record_word(addr, Width::U16);
+        }
+        self.inner.store_u16(addr, value);
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        if self.in_transaction() {
+            self.record_word(addr, Width::U8);
+        }
+        self.inner.store_u8(addr, value);
+    }
+
+    fn load_bytes(&mut self, addr: u64, out: &mut [u8]) {
+        self.inner.load_bytes(addr, out);
+    }
+
+    fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        if self.in_transaction() {
+            self.record_bytes(addr, bytes.len());
+        }
+        self.inner.store_bytes(addr, bytes);
+    }
+
+    fn fill(&mut self, addr: u64, len: usize, byte: u8) {
+        if self.in_transaction() {
+            self.record_bytes(addr, len);
+        }
+        self.inner.fill(addr, len, byte);
+    }
+
+    fn finish(&self) {
+        self.inner.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MemoryEmulator,
+        emulators::{journaled::JournaledMemory, paged::PagedMemoryFxHash},
+    };
+
+    #[test]
+    fn rollback_undoes_the_transaction() {
+        let mut mem = JournaledMemory::new(PagedMemoryFxHash::default());
+        mem.store_u64(0x1000, 1);
+        assert_eq!(mem.journal_len(), 0);
+
+        mem.begin();
+        mem.store_u64(0x1000, 2);
+        mem.store_u8(0x1001, 0xFF);
+        mem.store_u32(0x1FFE, 0xAABB_CCDD);
+        mem.fill(0x3000, 0x20, 0xCC);
+        mem.store_bytes(0x1004, &[9; 8]);
+        assert_eq!(mem.journal_len(), 5);
+
+        mem.rollback();
+        assert_eq!(mem.depth(), 0);
+        assert_eq!(mem.journal_len(), 0);
+        assert_eq!(mem.load_u64(0x1000), 1);
+        assert_eq!(mem.load_u64(0x1008), 0);
+        assert_eq!(mem.load_u32(0x1FFE), 0);
+        assert_eq!(mem.load_u64(0x3000), 0);
+    }
+
+    #[test]
+    fn nested_transactions() {
+        let mut mem = JournaledMemory::new(PagedMemoryFxHash::default());
+
+        mem.begin();
+        mem.store_u32(0x100, 1);
+
+        mem.begin();
+        mem.store_u32(0x100, 2);
+        mem.store_u32(0x200, 2);
+        mem.rollback();
+        assert_eq!(mem.load_u32(0x100), 1);
+        assert_eq!(mem.load_u32(0x200), 0);
+
+        mem.begin();
+        mem.store_u32(0x300, 3);
+        mem.commit();
+        assert_eq!(mem.depth(), 1);
+        assert_eq!(mem.load_u32(0x300), 3);
+
+        // rolling back the outer transaction undoes the committed inner one too
+        mem.rollback();
+        assert_eq!(mem.load_u32(0x100), 0);
+        assert_eq!(mem.load_u32(0x300), 0);
+
+        mem.begin();
+        mem.store_u32(0x100, 4);
+        mem.commit();
+        assert_eq!(mem.journal_len(), 0);
+        assert_eq!(mem.into_inner().load_u32(0x100), 4);
+    }
+
+    #[test]
+    #[should_panic(expected = "rollback without a matching begin")]
+    fn rollback_needs_a_transaction() {
+        JournaledMemory::new(PagedMemoryFxHash::default()).rollback();
+    }
+}
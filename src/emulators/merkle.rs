@@ -0,0 +1,313 @@
+use fxhash::{FxHashMap, FxHashSet};
+
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, PAGE_SIZE_4K, paged::PagedMemoryFxHash},
+    sha256::{Digest, Sha256},
+};
+
+/// Levels of the tree above the leaves, one per page index bit of 4 KiB pages
+const DEPTH: usize = 64 - PAGE_SHIFT_4K as usize;
+/// Domain separation of leaf and interior node hashes
+const LEAF_TAG: u8 = 0;
+const NODE_TAG: u8 = 1;
+
+/// Hash of a page's contents
+fn hash_leaf(page: &[u8]) -> Digest {
+    let mut hasher = Sha256::default();
+    hasher.update(&[LEAF_TAG]);
+    hasher.update(page);
+    hasher.finish()
+}
+
+/// Hash of an interior node from its children
+fn hash_node(left: &Digest, right: &Digest) -> Digest {
+    let mut hasher = Sha256::default();
+    hasher.update(&[NODE_TAG]);
+    hasher.update(left);
+    hasher.update(right);
+    hasher.finish()
+}
+
+/// Proof that a page holds given contents under a root
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MerkleProof {
+    pub page_idx: u64,
+    /// Contents of the page
+    pub page: Box<[u8]>,
+    /// Sibling of every node on the path from the leaf to the root, leaf level first
+    pub siblings: Vec<Digest>,
+}
+
+impl MerkleProof {
+    /// Whether the proof leads from the page contents to `root`
+    pub fn verify(&self, root: &Digest) -> bool {
+        if self.page.len() != PAGE_SIZE_4K || self.siblings.len() != DEPTH {
+            return false;
+        }
+
+        let mut hash = hash_leaf(&self.page);
+        for (level, sibling) in self.siblings.iter().enumerate() {
+            hash = if (self.page_idx >> level) & 1 == 0 {
+                hash_node(&hash, sibling)
+            } else {
+                hash_node(sibling, &hash)
+            };
+        }
+        hash == *root
+    }
+
+    /// Little endian value of `N` bytes at `addr`, which must lie in the proven page
+    pub fn read<const N: usize>(&self, addr: u64) -> [u8; N] {
+        assert_eq!(
+            addr >> PAGE_SHIFT_4K,
+            self.page_idx,
+            "address outside the page"
+        );
+        let offset = (addr & PAGE_MASK_4K) as usize;
+        self.page[offset..offset + N].try_into().unwrap()
+    }
+}
+
+/// Paged memory committed to by a sparse Merkle tree over the whole page index space
+/// untouched pages count as zero filled, so the root only depends on the contents,
+/// stores mark pages dirty and `commit_root` rehashes just those and their ancestors
+pub struct MerkleMemory {
+    pages: PagedMemoryFxHash,
+    /// Pages stored to since the last commit
+    dirty: FxHashSet<u64>,
+    /// Last page marked dirty, to skip the set on runs of stores to one page
+    last_dirty: Option<u64>,
+    /// Hashes differing from the empty subtree, per level, leaves first
+    nodes: Vec<FxHashMap<u64, Digest>>,
+    /// Hash of an all zero subtree at every level, the last one is the empty root
+    empty: Vec<Digest>,
+}
+
+impl Default for MerkleMemory {
+    fn default() -> Self {
+        let mut empty = vec![hash_leaf(&[0; PAGE_SIZE_4K])];
+        for level in 0..DEPTH {
+            empty.push(hash_node(&empty[level], &empty[level]));
+        }
+
+        Self {
+            pages: PagedMemoryFxHash::default(),
+            dirty: FxHashSet::default(),
+            last_dirty: None,
+            nodes: vec![FxHashMap::default(); DEPTH + 1],
+            empty,
+        }
+    }
+}
+
+impl MerkleMemory {
+    /// Number of pages stored to since the last commit
+    pub fn dirty_pages(&self) -> usize {
+        self.dirty.len()
+    }
+
+    #[inline]
+    fn mark_dirty(&mut self, addr: u64, len: usize) {
+        let first = addr >> PAGE_SHIFT_4K;
+        let last = addr.saturating_add(len as u64 - 1) >> PAGE_SHIFT_4K;
+        if first == last && self.last_dirty == Some(first) {
+            return;
+        }
+
+        self.dirty.extend(first..=last);
+        self.last_dirty = Some(last);
+    }
+
+    #[inline]
+    fn node(&self, level: usize, idx: u64) -> &Digest {
+        self.nodes[level].get(&idx).unwrap_or(&self.empty[level])
+    }
+
+    /// Store a node hash, keeping only those that differ from the empty subtree
+    fn set_node(&mut self, level: usize, idx: u64, hash: Digest) {
+        if hash == self.empty[level] {
+            self.nodes[level].remove(&idx);
+        } else {
+            self.nodes[level].insert(idx, hash);
+        }
+    }
+
+    /// Rehash the dirty pages and their ancestors, returning the new root
+    pub fn commit_root(&mut self) -> Digest {
+        let mut dirty: Vec<u64> = self.dirty.drain().collect();
+        self.last_dirty = None;
+        dirty.sort_unstable();
+
+        for &idx in &dirty {
+            let hash = match self.pages.page(idx) {
+                Some(page) => hash_leaf(page),
+                None => self.empty[0],
+            };
+            self.set_node(0, idx, hash);
+        }
+
+        for level in 0..DEPTH {
+            // sorted, so siblings sharing a parent are next to each other
+            for idx in dirty.iter_mut() {
+                *idx >>= 1;
+            }
+            dirty.dedup();
+
+            for &idx in &dirty {
+                let hash = hash_node(self.node(level, idx * 2), self.node(level, idx * 2 + 1));
+                self.set_node(level + 1, idx, hash);
+            }
+        }
+
+        *self.node(DEPTH, 0)
+    }
+
+    /// Commit the root and prove the contents of the page holding `addr`
+    pub fn prove(&mut self, addr: u64) -> (Digest, MerkleProof) {
+        let root = self.commit_root();
+
+        let page_idx = addr >> PAGE_SHIFT_4K;
+        let page = match self.pages.page(page_idx) {
+            Some(page) => page.into(),
+            None => vec![0; PAGE_SIZE_4K].into_boxed_slice(),
+        };
+        let siblings = (0..DEPTH)
+            .map(|level| *self.node(level, (page_idx >> level) ^ 1))
+            .collect();
+
+        (
+            root,
+            MerkleProof {
+                page_idx,
+                page,
+                siblings,
+            },
+        )
+    }
+}
+
+impl MemoryEmulator for MerkleMemory {
+    fn name(&self) -> String {
+        "MerkleMem(SHA-256)".to_string()
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        self.pages.load_u64(addr)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        self.pages.load_u32(addr)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        self.pages.load_u16(addr)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.pages.load_u8(addr)
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.mark_dirty(addr, 8);
+        self.pages.store_u64(addr, value);
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.mark_dirty(addr, 4);
+        self.pages.store_u32(addr, value);
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.mark_dirty(addr, 2);
+        self.pages.store_u16(addr, value);
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.mark_dirty(addr, 1);
+        self.pages.store_u8(addr, value);
+    }
+
+    fn load_bytes(&mut self, addr: u64, out: &mut [u8]) {
+        self.pages.load_bytes(addr, out);
+    }
+
+    fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        if !bytes.is_empty() {
+            self.mark_dirty(addr, bytes.len());
+        }
+        self.pages.store_bytes(addr, bytes);
+    }
+
+    fn fill(&mut self, addr: u64, len: usize, byte: u8) {
+        if len > 0 {
+            self.mark_dirty(addr, len);
+        }
+        self.pages.fill(addr, len, byte);
+    }
+
+    fn finish(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MemoryEmulator,
+        emulators::merkle::{DEPTH, MerkleMemory},
+    };
+
+    #[test]
+    fn root_depends_only_on_contents() {
+        let mut a = MerkleMemory::default();
+        let empty_root = a.commit_root();
+
+        a.store_u64(0x1000, 1);
+        a.store_u64(0xFFFF_FFFF_FFFF_FF00, 2);
+        let root = a.commit_root();
+        assert_ne!(root, empty_root);
+        assert_eq!(a.dirty_pages(), 0);
+
+        // same contents, reached in another order and through an overwritten value
+        let mut b = MerkleMemory::default();
+        b.store_u64(0xFFFF_FFFF_FFFF_FF00, 2);
+        b.store_u64(0x1000, 7);
+        b.commit_root();
+        b.store_u64(0x1000, 1);
+        assert_eq!(b.commit_root(), root);
+
+        // writing zeros back brings the empty tree back, and prunes every stored node
+        a.store_u64(0x1000, 0);
+        a.store_u64(0xFFFF_FFFF_FFFF_FF00, 0);
+        assert_eq!(a.commit_root(), empty_root);
+        assert!(a.nodes.iter().all(|level| level.is_empty()));
+    }
+
+    #[test]
+    fn proofs_verify_against_the_root() {
+        let mut mem = MerkleMemory::default();
+        mem.store_u32(0x1FFE, 0xAABB_CCDD);
+        mem.store_u64(0x5000, 42);
+
+        let (root, proof) = mem.prove(0x5008);
+        assert_eq!(proof.siblings.len(), DEPTH);
+        assert!(proof.verify(&root));
+        assert_eq!(u64::from_le_bytes(proof.read(0x5000)), 42);
+
+        // the page straddled by the store was marked dirty as well
+        let (root, proof) = mem.prove(0x2000);
+        assert!(proof.verify(&root));
+        assert_eq!(proof.read::<2>(0x2000), [0xBB, 0xAA]);
+
+        // untouched pages are proven to be zero
+        let (root, proof) = mem.prove(0x9000);
+        assert!(proof.verify(&root));
+
+        let mut forged = proof.clone();
+        forged.page[0] = 1;
+        assert!(!forged.verify(&root));
+
+        mem.store_u8(0x9000, 1);
+        let (new_root, _) = mem.prove(0x9000);
+        assert!(!proof.verify(&new_root));
+    }
+}
@@ -0,0 +1,212 @@
+use std::{io, ptr::NonNull};
+
+use crate::{MemoryEmulator, emulators::paged::PagedMemoryFxHash};
+
+/// Number of address bits covered by the low window, [0, 2^46)
+const LOW_WINDOW_BITS: u32 = 46;
+/// Number of address bits covered by the high window, [2^64 - 2^32, 2^64)
+/// sized for stacks growing down from the top of the address space
+const HIGH_WINDOW_BITS: u32 = 32;
+
+/// An anonymous mapping reserved with `MAP_NORESERVE`
+/// the kernel zero fills its pages on first touch
+struct Reservation {
+    ptr: NonNull<u8>,
+    len: u64,
+}
+
+impl Reservation {
+    fn new(len: u64) -> io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len as usize,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(Self {
+            ptr: NonNull::new(ptr as *mut u8).unwrap(),
+            len,
+        })
+    }
+
+    /// Host pointer to `len` bytes at `offset`, if they fall inside the reservation
+    #[inline]
+    fn at(&self, offset: u64, len: usize) -> Option<*mut u8> {
+        if offset < self.len && len as u64 <= self.len - offset {
+            Some(unsafe { self.ptr.as_ptr().add(offset as usize) })
+        } else {
+            None
+        }
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr.as_ptr() as *mut libc::c_void, self.len as usize);
+        }
+    }
+}
+
+/// Memory mapped straight onto two reserved host windows, a low one and a high one
+/// a load or store inside a window is one add and one dereference, the kernel does the paging
+/// addresses outside both windows fall back to a `PagedMemory`
+pub struct MmapFlatMemory {
+    low: Reservation,
+    high: Reservation,
+    /// Guest address of the first byte of the high window
+    high_base: u64,
+    fallback: PagedMemoryFxHash,
+}
+
+impl Default for MmapFlatMemory {
+    fn default() -> Self {
+        Self::new(LOW_WINDOW_BITS, HIGH_WINDOW_BITS)
+            .unwrap_or_else(|e| panic!("failed to reserve guest memory: {}", e))
+    }
+}
+
+impl MmapFlatMemory {
+    /// Reserve a window over [0, 2^low_bits) and one over [2^64 - 2^high_bits, 2^64)
+    pub fn new(low_bits: u32, high_bits: u32) -> io::Result<Self> {
+        assert!(low_bits < 64 && high_bits < 64, "window too large");
+
+        let high_len = 1u64 << high_bits;
+        Ok(Self {
+            low: Reservation::new(1 << low_bits)?,
+            high: Reservation::new(high_len)?,
+            high_base: high_len.wrapping_neg(),
+            fallback: PagedMemoryFxHash::default(),
+        })
+    }
+
+    /// Host pointer to `len` guest bytes at `addr`, if they fall inside one window
+    #[inline]
+    fn host_ptr(&self, addr: u64, len: usize) -> Option<*mut u8> {
+        self.low
+            .at(addr, len)
+            .or_else(|| self.high.at(addr.wrapping_sub(self.high_base), len))
+    }
+
+    #[inline]
+    fn read<const N: usize>(&self, addr: u64) -> [u8; N] {
+        match self.host_ptr(addr, N) {
+            Some(ptr) => unsafe { (ptr as *const [u8; N]).read_unaligned() },
+            None => self.read_slow(addr),
+        }
+    }
+
+    #[inline]
+    fn write<const N: usize>(&mut self, addr: u64, bytes: [u8; N]) {
+        match self.host_ptr(addr, N) {
+            Some(ptr) => unsafe { (ptr as *mut [u8; N]).write_unaligned(bytes) },
+            None => self.write_slow(addr, &bytes),
+        }
+    }
+
+    /// Byte at a time for accesses outside the windows or straddling their edges
+    #[cold]
+    fn read_slow<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let _ = addr
+            .checked_add(N as u64 - 1)
+            .unwrap_or_else(|| panic!("read out of range: 0x{:x}", addr));
+
+        let mut out = [0u8; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            let addr = addr + i as u64;
+            *byte = match self.host_ptr(addr, 1) {
+                Some(ptr) => unsafe { *ptr },
+                None => self.fallback.read_n_bytes_const::<1>(addr)[0],
+            };
+        }
+        out
+    }
+
+    #[cold]
+    fn write_slow(&mut self, addr: u64, bytes: &[u8]) {
+        let _ = addr
+            .checked_add(bytes.len() as u64 - 1)
+            .unwrap_or_else(|| panic!("write out of range: 0x{:x}", addr));
+
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = addr + i as u64;
+            match self.host_ptr(addr, 1) {
+                Some(ptr) => unsafe { *ptr = *byte },
+                None => self.fallback.write_n_bytes(addr, &[*byte]),
+            }
+        }
+    }
+}
+
+impl MemoryEmulator for MmapFlatMemory {
+    fn name(&self) -> String {
+        "MmapFlat".to_string()
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        u64::from_le_bytes(self.read::<8>(addr))
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        u32::from_le_bytes(self.read::<4>(addr))
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        u16::from_le_bytes(self.read::<2>(addr))
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn finish(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MemoryEmulator, emulators::mmap_flat::MmapFlatMemory};
+
+    #[test]
+    fn accesses_straddling_a_window_edge() {
+        let mut mem = MmapFlatMemory::new(16, 12).unwrap();
+
+        // low window ends at 0x10000, the rest of the store lands in the fallback
+        mem.store_u64(0xFFFC, 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u64(0xFFFC), 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u32(0x10000), 0x0123_4567);
+        assert!(mem.host_ptr(0x10000, 1).is_none());
+
+        // high window starts at 2^64 - 2^12
+        let high_base = 0u64.wrapping_sub(1 << 12);
+        mem.store_u32(high_base - 2, 0xAABB_CCDD);
+        assert_eq!(mem.load_u16(high_base), 0xAABB);
+        assert!(mem.host_ptr(high_base, 1).is_some());
+
+        assert_eq!(mem.load_u64(0x1_0000_0000), 0);
+    }
+}
@@ -1,3 +1,133 @@
+pub mod journaled;
+pub mod merkle;
+pub mod mmap_flat;
 pub mod noop;
+pub mod page_table;
 pub mod paged;
 pub mod paged_last_cache;
+pub mod paged_split_cache;
+pub mod paged_tlb;
+pub mod radix;
+pub mod region;
+pub mod timestamped;
+
+use std::ops::Range;
+
+/// Page shift of 4 KiB pages
+pub const PAGE_SHIFT_4K: u32 = 12;
+/// Page shift of 64 KiB pages
+pub const PAGE_SHIFT_64K: u32 = 16;
+/// Page shift of 2 MiB pages
+pub const PAGE_SHIFT_2M: u32 = 21;
+
+/// Total number of entries in a 4 KiB page, the page of backends without a page size parameter
+pub(crate) const PAGE_SIZE_4K: usize = 1 << PAGE_SHIFT_4K;
+/// Mask to get the last `PAGE_SHIFT_4K` bits of an address
+pub(crate) const PAGE_MASK_4K: u64 = (PAGE_SIZE_4K as u64) - 1;
+
+/// The part of an access lying within one page
+pub(crate) struct PageChunk {
+    /// Index of the page
+    pub idx: u64,
+    /// Bytes of the page covered by the chunk
+    pub page: Range<usize>,
+    /// Bytes of the access covered by the chunk
+    pub buf: Range<usize>,
+}
+
+/// Splits an access at page boundaries, see `page_chunks`
+pub(crate) struct PageChunks {
+    addr: u64,
+    done: usize,
+    len: usize,
+    page_shift: u32,
+}
+
+/// The chunks of `len` bytes at `addr` for pages of `1 << page_shift` bytes, lowest first
+/// panics with "{access} out of range" if the bytes run past the end of the address space
+#[inline]
+pub(crate) fn page_chunks(addr: u64, len: usize, page_shift: u32, access: &str) -> PageChunks {
+    if len > 0 {
+        let _ = addr
+            .checked_add(len as u64 - 1)
+            .unwrap_or_else(|| panic!("{} out of range: 0x{:x}", access, addr));
+    }
+
+    PageChunks {
+        addr,
+        done: 0,
+        len,
+        page_shift,
+    }
+}
+
+impl Iterator for PageChunks {
+    type Item = PageChunk;
+
+    #[inline]
+    fn next(&mut self) -> Option<PageChunk> {
+        if self.done == self.len {
+            return None;
+        }
+
+        let page_size = 1usize << self.page_shift;
+        let offset = (self.addr as usize) & (page_size - 1);
+        let chunk = (self.len - self.done).min(page_size - offset);
+        let item = PageChunk {
+            idx: self.addr >> self.page_shift,
+            page: offset..offset + chunk,
+            buf: self.done..self.done + chunk,
+        };
+
+        // steps past u64::MAX after the last byte of memory, with nothing left to visit
+        self.addr = self.addr.wrapping_add(chunk as u64);
+        self.done += chunk;
+        Some(item)
+    }
+}
+
+/// Suffix distinguishing emulator names by page size
+/// empty for the default 4 KiB pages, so existing names stay stable
+pub(crate) fn page_size_label(page_shift: u32) -> String {
+    match page_shift {
+        PAGE_SHIFT_4K => String::new(),
+        shift if shift >= 20 => format!("{}M", 1u64 << (shift - 20)),
+        shift if shift >= 10 => format!("{}K", 1u64 << (shift - 10)),
+        shift => format!("{}B", 1u64 << shift),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulators::{PAGE_SHIFT_4K, page_chunks};
+
+    #[test]
+    fn chunks_split_at_page_boundaries() {
+        let chunks: Vec<_> = page_chunks(0x1FFE, 0x1004, PAGE_SHIFT_4K, "read")
+            .map(|c| (c.idx, c.page, c.buf))
+            .collect();
+        assert_eq!(
+            chunks,
+            [
+                (1, 0xFFE..0x1000, 0..2),
+                (2, 0..0x1000, 2..0x1002),
+                (3, 0..2, 0x1002..0x1004),
+            ]
+        );
+        assert_eq!(page_chunks(0x1000, 0, PAGE_SHIFT_4K, "read").count(), 0);
+    }
+
+    #[test]
+    fn chunks_reach_the_last_byte_of_memory() {
+        let chunks: Vec<_> = page_chunks(u64::MAX - 1, 2, PAGE_SHIFT_4K, "write")
+            .map(|c| (c.idx, c.page))
+            .collect();
+        assert_eq!(chunks, [(u64::MAX >> PAGE_SHIFT_4K, 0xFFE..0x1000)]);
+    }
+
+    #[test]
+    #[should_panic(expected = "write out of range: 0xffffffffffffffff")]
+    fn chunks_past_the_end_of_memory() {
+        page_chunks(u64::MAX, 2, PAGE_SHIFT_4K, "write");
+    }
+}
@@ -0,0 +1,296 @@
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, PAGE_SIZE_4K, page_chunks},
+};
+
+type Page = Box<[u8; PAGE_SIZE_4K]>;
+
+/// Key of an unused slot
+/// the table is only keyed by page indices of 4 KiB pages, at most 52 bits wide,
+/// so it never collides with a real key
+const EMPTY: u64 = u64::MAX;
+/// Number of slots allocated by the first insert
+const INITIAL_CAPACITY: usize = 64;
+/// 2^64 / golden ratio, spreads consecutive page ids over the whole table
+const FIB_MULTIPLIER: u64 = 0x9E37_79B9_7F4A_7C15;
+
+/// A key inline next to its value, so a probe only touches the slot array
+struct Slot<V> {
+    key: u64,
+    value: Option<V>,
+}
+
+impl<V> Slot<V> {
+    const VACANT: Self = Slot {
+        key: EMPTY,
+        value: None,
+    };
+}
+
+/// Open-addressing hash table from page ids to pages
+/// linear probing over a power-of-two slot array kept at most 3/4 full,
+/// entries are never removed, so probing needs no tombstones
+pub(crate) struct PageTable<V> {
+    slots: Box<[Slot<V>]>,
+    len: usize,
+    /// `64 - log2(capacity)`, the hash keeps the top bits of the product
+    shift: u32,
+}
+
+impl<V> Default for PageTable<V> {
+    fn default() -> Self {
+        Self {
+            slots: Box::new([]),
+            len: 0,
+            shift: 64,
+        }
+    }
+}
+
+impl<V> PageTable<V> {
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.len
+    }
+
+    #[cfg(test)]
+    fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Number of slots, entries included
+    #[cfg(test)]
+    fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Multiplicative hash of a page id
+    /// the high bits of the product depend on every bit of the key
+    #[inline]
+    fn home_slot(&self, key: u64) -> usize {
+        (key.wrapping_mul(FIB_MULTIPLIER) >> self.shift) as usize
+    }
+
+    /// Index of the slot holding `key`, or of the empty slot ending its probe sequence
+    /// the table must have at least one empty slot
+    #[inline]
+    fn find_slot(&self, key: u64) -> usize {
+        let mask = self.slots.len() - 1;
+        let mut i = self.home_slot(key);
+        loop {
+            let slot_key = self.slots[i].key;
+            if slot_key == key || slot_key == EMPTY {
+                return i;
+            }
+            i = (i + 1) & mask;
+        }
+    }
+
+    #[inline]
+    pub fn get(&self, key: u64) -> Option<&V> {
+        debug_assert_ne!(key, EMPTY);
+        if self.slots.is_empty() {
+            return None;
+        }
+        self.slots[self.find_slot(key)].value.as_ref()
+    }
+
+    #[inline]
+    pub fn get_or_insert_with(&mut self, key: u64, f: impl FnOnce() -> V) -> &mut V {
+        debug_assert_ne!(key, EMPTY);
+        // grow before probing, so the slot found stays valid
+        if (self.len + 1) * 4 > self.slots.len() * 3 {
+            self.grow();
+        }
+
+        let i = self.find_slot(key);
+        let slot = &mut self.slots[i];
+        if slot.key == EMPTY {
+            slot.key = key;
+            self.len += 1;
+        }
+        slot.value.get_or_insert_with(f)
+    }
+
+    /// Double the number of slots and reinsert every entry
+    #[cold]
+    fn grow(&mut self) {
+        let capacity = (self.slots.len() * 2).max(INITIAL_CAPACITY);
+        let slots = std::iter::repeat_with(|| Slot::VACANT)
+            .take(capacity)
+            .collect();
+        let old = std::mem::replace(&mut self.slots, slots);
+        self.shift = 64 - capacity.trailing_zeros();
+
+        for slot in old.into_vec() {
+            if slot.key != EMPTY {
+                let i = self.find_slot(slot.key);
+                self.slots[i] = slot;
+            }
+        }
+    }
+}
+
+/// Memory backed by `PageTable`, a hash table specialised for u64 page ids
+/// instead of the general purpose `HashMap`
+#[derive(Default)]
+pub struct PageTableMemory {
+    pages: PageTable<Page>,
+}
+
+impl MemoryEmulator for PageTableMemory {
+    fn name(&self) -> String {
+        "PageTableMem".to_string()
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        let bytes = self.read_n_bytes_const::<8>(addr);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        let bytes = self.read_n_bytes_const::<4>(addr);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        let bytes = self.read_n_bytes_const::<2>(addr);
+        u16::from_le_bytes(bytes)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read_n_bytes_const::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn finish(&self) {}
+}
+
+impl PageTableMemory {
+    /// Return the page index given the address
+    #[inline]
+    pub fn page_idx(addr: u64) -> u64 {
+        // addr = [PAGE_ID][PAGE_SHIFT]
+        addr >> PAGE_SHIFT_4K
+    }
+
+    /// Return the entry index within a page
+    /// given an address
+    #[inline]
+    pub fn page_offset(addr: u64) -> usize {
+        (addr & PAGE_MASK_4K) as usize
+    }
+
+    pub(crate) fn read_n_bytes_const<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let mut out = [0u8; N];
+        self.read_into(addr, &mut out);
+        out
+    }
+
+    /// Read n contiguous bytes from memory
+    /// overwrites all of `out`, bytes of unallocated pages read as zero
+    fn read_into(&self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT_4K, "read") {
+            match self.pages.get(chunk.idx) {
+                Some(page) => out[chunk.buf].copy_from_slice(&page[chunk.page]),
+                None => out[chunk.buf].fill(0),
+            }
+        }
+    }
+
+    /// Write n contiguous bytes into memory
+    /// Handles cross page writing
+    pub(crate) fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for chunk in page_chunks(addr, bytes.len(), PAGE_SHIFT_4K, "write") {
+            let page = self
+                .pages
+                .get_or_insert_with(chunk.idx, || Box::new([0; PAGE_SIZE_4K]));
+            page[chunk.page].copy_from_slice(&bytes[chunk.buf]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulators::page_table::{INITIAL_CAPACITY, PageTable, PageTableMemory};
+
+    #[test]
+    fn entries_survive_growth() {
+        let mut table = PageTable::default();
+        assert!(table.get(0).is_none());
+
+        // strided ids, as produced by a scan over large objects
+        for i in 0..10_000u64 {
+            *table.get_or_insert_with(i << 8, || 0) += i;
+        }
+        assert_eq!(table.len(), 10_000);
+        assert!(table.capacity() * 3 >= table.len() * 4);
+
+        for i in 0..10_000u64 {
+            assert_eq!(table.get(i << 8), Some(&i));
+        }
+        assert!(table.get(1).is_none());
+    }
+
+    #[test]
+    fn existing_entries_are_not_replaced() {
+        let mut table = PageTable::default();
+        *table.get_or_insert_with(7, || 1) += 1;
+        assert_eq!(*table.get_or_insert_with(7, || 100), 2);
+        assert_eq!(table.len(), 1);
+        assert_eq!(table.capacity(), INITIAL_CAPACITY);
+    }
+
+    #[test]
+    fn colliding_keys_are_probed() {
+        let mut table = PageTable::default();
+        table.get_or_insert_with(0, || 0);
+        // keys sharing the home slot of 0
+        let colliding: Vec<u64> = (1..)
+            .filter(|&k| table.home_slot(k) == table.home_slot(0))
+            .take(3)
+            .collect();
+        for &key in &colliding {
+            table.get_or_insert_with(key, || key);
+        }
+
+        assert_eq!(table.get(0), Some(&0));
+        for &key in &colliding {
+            assert_eq!(table.get(key), Some(&key));
+        }
+    }
+
+    #[test]
+    fn reads_do_not_allocate() {
+        let mut mem = PageTableMemory::default();
+        assert_eq!(mem.read_n_bytes_const::<8>(0x1234 << 12), [0; 8]);
+        assert!(mem.pages.is_empty());
+
+        mem.write_n_bytes(0x1234 << 12, &[1]);
+        assert!(mem.pages.get(0x1234).is_some());
+        assert!(mem.pages.get(0x1235).is_none());
+    }
+
+    #[test]
+    fn top_page_is_addressable() {
+        let mut mem = PageTableMemory::default();
+        mem.write_n_bytes(u64::MAX - 7, &[0xAB; 8]);
+        assert_eq!(mem.read_n_bytes_const::<8>(u64::MAX - 7), [0xAB; 8]);
+        assert!(mem.pages.get(u64::MAX >> 12).is_some());
+    }
+}
@@ -1,34 +1,115 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, io, ops::Deref, path::Path, sync::Arc};
 
 use crate::{
     MemoryEmulator,
+    emulators::{PAGE_SHIFT_2M, PAGE_SHIFT_4K, PAGE_SHIFT_64K, page_chunks, page_size_label},
+    memory_image::{self, ImageHeader},
     named_hasher::{AHash, FxHash, NamedHasher, NoHashU64, Sip},
+    segments::{PageContents, SegmentPages},
 };
 
-/// Number of bits to describe entries in a page
-const PAGE_SHIFT: u64 = 12;
-/// Total number of entries in a page
-const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
-/// Mask to get the last `PAGE_SHIFT` bits of an address
-const PAGE_MASK: u64 = (PAGE_SIZE as u64) - 1;
-/// Max memory address
-const MAX_ADDR: u64 = u64::MAX;
+/// Storage of a page of `1 << PAGE_SHIFT` bytes
+/// `Box<[u8]>` owns its page outright, `Arc<[u8]>` can share it with snapshots and forks
+/// and copies it on the first write after sharing
+pub trait PageBuf: Deref<Target = [u8]> {
+    /// Distinguishes emulator names, empty for pages that are never shared
+    const LABEL: &'static str;
 
-type Page = Box<[u8; PAGE_SIZE]>;
+    /// A zero filled page of `size` bytes
+    fn zeroed(size: usize) -> Self;
+
+    /// The page contents for writing, copied first if the page is shared
+    fn make_mut(&mut self) -> &mut [u8];
+
+    /// The page contents for writing, `None` while the page is shared
+    fn get_mut(&mut self) -> Option<&mut [u8]>;
+}
+
+impl PageBuf for Box<[u8]> {
+    const LABEL: &'static str = "";
+
+    fn zeroed(size: usize) -> Self {
+        vec![0; size].into_boxed_slice()
+    }
+
+    #[inline]
+    fn make_mut(&mut self) -> &mut [u8] {
+        self
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> Option<&mut [u8]> {
+        Some(self)
+    }
+}
+
+impl PageBuf for Arc<[u8]> {
+    const LABEL: &'static str = "Cow";
+
+    fn zeroed(size: usize) -> Self {
+        vec![0; size].into()
+    }
+
+    #[inline]
+    fn make_mut(&mut self) -> &mut [u8] {
+        Arc::make_mut(self)
+    }
+
+    #[inline]
+    fn get_mut(&mut self) -> Option<&mut [u8]> {
+        Arc::get_mut(self)
+    }
+}
 
 pub type PagedMemoryDefault = PagedMemory<Sip>;
 pub type PagedMemoryAHash = PagedMemory<AHash>;
 pub type PagedMemoryFxHash = PagedMemory<FxHash>;
 pub type PagedMemoryNoHashU64 = PagedMemory<NoHashU64>;
 
+pub type PagedMemory4K<S> = PagedMemory<S, PAGE_SHIFT_4K>;
+pub type PagedMemory64K<S> = PagedMemory<S, PAGE_SHIFT_64K>;
+pub type PagedMemory2M<S> = PagedMemory<S, PAGE_SHIFT_2M>;
+
+/// Copy-on-write pages, for snapshots, forks and `replay_segments`
+/// every store checks whether its page is shared, so the plain types are faster otherwise
+pub type PagedMemoryCow<S, const PAGE_SHIFT: u32 = PAGE_SHIFT_4K> =
+    PagedMemory<S, PAGE_SHIFT, Arc<[u8]>>;
+pub type PagedMemoryCowFxHash = PagedMemoryCow<FxHash>;
+
+/// The pages of a copy-on-write paged memory at one point in time
+/// taking one only bumps reference counts, pages are copied once either side writes to them
+pub struct Snapshot<S: NamedHasher, const PAGE_SHIFT: u32 = PAGE_SHIFT_4K> {
+    pub(crate) pages: HashMap<u64, Arc<[u8]>, S>,
+}
+
+impl<S: NamedHasher, const PAGE_SHIFT: u32> Snapshot<S, PAGE_SHIFT> {
+    /// Number of pages captured
+    pub fn page_count(&self) -> usize {
+        self.pages.len()
+    }
+}
+
+/// `PAGE_SHIFT` is the number of bits to describe entries in a page
+/// `B` stores every page, see `PageBuf`
 #[derive(Default)]
-pub struct PagedMemory<S: NamedHasher> {
-    pages: HashMap<u64, Page, S>,
+pub struct PagedMemory<
+    S: NamedHasher,
+    const PAGE_SHIFT: u32 = PAGE_SHIFT_4K,
+    B: PageBuf = Box<[u8]>,
+> {
+    pages: HashMap<u64, B, S>,
 }
 
-impl<S: NamedHasher> MemoryEmulator for PagedMemory<S> {
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> MemoryEmulator
+    for PagedMemory<S, PAGE_SHIFT, B>
+{
     fn name(&self) -> String {
-        format!("PagedMem({})", S::NAME)
+        format!(
+            "PagedMem{}{}({})",
+            B::LABEL,
+            page_size_label(PAGE_SHIFT),
+            S::NAME
+        )
     }
 
     fn load_u64(&mut self, addr: u64) -> u64 {
@@ -51,25 +132,50 @@ impl<S: NamedHasher> MemoryEmulator for PagedMemory<S> {
     }
 
     fn store_u64(&mut self, addr: u64, value: u64) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u32(&mut self, addr: u64, value: u32) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u16(&mut self, addr: u64, value: u16) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u8(&mut self, addr: u64, value: u8) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
+    }
+
+    fn load_bytes(&mut self, addr: u64, out: &mut [u8]) {
+        self.read_into(addr, out);
+    }
+
+    fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        self.write_n_bytes(addr, bytes);
+    }
+
+    fn fill(&mut self, addr: u64, len: usize, byte: u8) {
+        self.fill_n_bytes(addr, len, byte);
     }
 
     fn finish(&self) {}
 }
 
-impl<S: NamedHasher> PagedMemory<S> {
+impl<S: NamedHasher, const PAGE_SHIFT: u32> SegmentPages for PagedMemoryCow<S, PAGE_SHIFT> {
+    const PAGE_SHIFT: u32 = PAGE_SHIFT;
+
+    fn share_page(&mut self, idx: u64) -> Option<PageContents> {
+        self.pages.get(&idx).cloned().map(PageContents::new)
+    }
+}
+
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> PagedMemory<S, PAGE_SHIFT, B> {
+    /// Total number of entries in a page
+    pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+    /// Mask to get the last `PAGE_SHIFT` bits of an address
+    const PAGE_MASK: u64 = (Self::PAGE_SIZE as u64) - 1;
+
     /// Return the page index given the address
     #[inline]
     pub fn page_idx(addr: u64) -> u64 {
@@ -81,91 +187,209 @@ impl<S: NamedHasher> PagedMemory<S> {
     /// given an address
     #[inline]
     pub fn page_offset(addr: u64) -> usize {
-        (addr & PAGE_MASK) as usize
+        (addr & Self::PAGE_MASK) as usize
     }
 
     /// Returns a mutable reference to a page given an address
-    /// lazy allocates the page if needed
+    /// lazy allocates the page if needed, copies it if a snapshot shares it
     #[inline]
-    fn ensure_page(&mut self, idx: u64) -> &mut Page {
+    fn ensure_page(&mut self, idx: u64) -> &mut [u8] {
         self.pages
             .entry(idx)
-            .or_insert_with(|| Box::new([0; PAGE_SIZE]))
+            .or_insert_with(|| B::zeroed(Self::PAGE_SIZE))
+            .make_mut()
     }
+}
 
-    pub(crate) fn read_n_bytes_const<const N: usize>(&self, addr: u64) -> [u8; N] {
-        let mut out = [0u8; N];
-        self.read_into(addr, &mut out);
-        out
+impl<S: NamedHasher + Clone, const PAGE_SHIFT: u32> PagedMemoryCow<S, PAGE_SHIFT> {
+    /// Capture the current contents of memory
+    pub fn snapshot(&self) -> Snapshot<S, PAGE_SHIFT> {
+        Snapshot {
+            pages: self.pages.clone(),
+        }
     }
 
-    /// Read n contiguous bytes from memory
-    /// assumes that out is zeroed out
-    fn read_into(&self, addr: u64, out: &mut [u8]) {
-        let len = out.len();
-        if len == 0 {
-            return;
+    /// Rewind memory to a snapshot
+    /// the snapshot stays valid, so it can be restored again
+    pub fn restore(&mut self, snapshot: &Snapshot<S, PAGE_SHIFT>) {
+        self.pages = snapshot.pages.clone();
+    }
+
+    /// A new memory with the same contents, sharing every page with this one
+    /// either side copies a shared page on its first write to it
+    pub fn fork(&self) -> Self {
+        Self {
+            pages: self.pages.clone(),
         }
+    }
+}
 
-        let end = addr
-            .checked_add(len as u64 - 1)
-            .unwrap_or_else(|| panic!("read out of range: 0x{:x}", addr));
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> PagedMemory<S, PAGE_SHIFT, B> {
+    /// Contents of an allocated page given its index
+    pub(crate) fn page(&self, idx: u64) -> Option<&[u8]> {
+        self.pages.get(&idx).map(|page| &page[..])
+    }
 
-        if end > MAX_ADDR {
-            panic!("write out of range: 0x{:x}", addr);
-        }
+    /// Every allocated page with its index, in no particular order
+    pub fn pages(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.pages.iter().map(|(idx, page)| (*idx, &page[..]))
+    }
 
-        let mut curr_addr = addr;
-        let mut bytes_left = len;
-        let mut dst_off = 0;
+    /// Write the contents of memory to a file, see `memory_image::write_image`
+    pub fn save_image<P: AsRef<Path>>(&self, path: P) -> io::Result<ImageHeader> {
+        memory_image::save_image(path, PAGE_SHIFT, self.pages())
+    }
 
-        while bytes_left > 0 {
-            let idx = Self::page_idx(curr_addr);
-            let offset = Self::page_offset(curr_addr);
+    #[inline]
+    pub(crate) fn read_n_bytes_const<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let offset = Self::page_offset(addr);
+        // fast path, the access lies within one page
+        if offset + N <= Self::PAGE_SIZE {
+            return match self.pages.get(&Self::page_idx(addr)) {
+                Some(page) => unsafe {
+                    (page.as_ptr().add(offset) as *const [u8; N]).read_unaligned()
+                },
+                None => [0; N],
+            };
+        }
 
-            let chunk = bytes_left.min(PAGE_SIZE - offset);
+        let mut out = [0u8; N];
+        self.read_into(addr, &mut out);
+        out
+    }
+
+    #[inline]
+    pub(crate) fn write_n_bytes_const<const N: usize>(&mut self, addr: u64, bytes: [u8; N]) {
+        let offset = Self::page_offset(addr);
+        // fast path, the access lies within one page
+        if offset + N <= Self::PAGE_SIZE {
+            let page = self.ensure_page(Self::page_idx(addr));
+            unsafe { (page.as_mut_ptr().add(offset) as *mut [u8; N]).write_unaligned(bytes) };
+            return;
+        }
 
-            if let Some(page) = self.pages.get(&idx) {
-                out[dst_off..dst_off + chunk].copy_from_slice(&page[offset..offset + chunk]);
-            } // else leave as zeros
+        self.write_n_bytes(addr, &bytes);
+    }
 
-            curr_addr += chunk as u64;
-            dst_off += chunk;
-            bytes_left -= chunk
+    /// Read n contiguous bytes from memory
+    fn read_into(&self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT, "read") {
+            match self.pages.get(&chunk.idx) {
+                Some(page) => out[chunk.buf].copy_from_slice(&page[chunk.page]),
+                None => out[chunk.buf].fill(0),
+            }
         }
     }
 
     /// Write n contiguous bytes into memory
     /// Handles cross page writing
     pub(crate) fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
-        if bytes.is_empty() {
-            return;
-        }
+        self.write_chunks(addr, bytes.len(), |src_off, dst| {
+            dst.copy_from_slice(&bytes[src_off..src_off + dst.len()])
+        });
+    }
 
-        let end = addr
-            .checked_add(bytes.len() as u64 - 1)
-            .unwrap_or_else(|| panic!("write out of range: 0x{:x}", addr));
+    /// Fill len contiguous bytes of memory with a byte
+    fn fill_n_bytes(&mut self, addr: u64, len: usize, byte: u8) {
+        self.write_chunks(addr, len, |_, dst| dst.fill(byte));
+    }
 
-        if addr > MAX_ADDR || end > MAX_ADDR {
-            panic!("write out of range: 0x{:x}", addr);
+    /// Hand every page chunk of `len` bytes at `addr` to `f`
+    /// along with the offset of the chunk within the range, lazy allocates the pages
+    #[inline]
+    fn write_chunks(&mut self, addr: u64, len: usize, mut f: impl FnMut(usize, &mut [u8])) {
+        for chunk in page_chunks(addr, len, PAGE_SHIFT, "write") {
+            let page = self.ensure_page(chunk.idx);
+            f(chunk.buf.start, &mut page[chunk.page]);
         }
+    }
+}
 
-        let mut curr_addr = addr;
-        let mut bytes_left = bytes.len();
-        let mut src_off = 0;
+#[cfg(test)]
+mod tests {
+    use crate::{MemoryEmulator, emulators::paged::PagedMemoryCowFxHash};
 
-        while bytes_left > 0 {
-            let idx = Self::page_idx(curr_addr);
-            let offset = Self::page_offset(curr_addr);
+    #[test]
+    fn restore_rewinds_to_the_snapshot() {
+        let mut mem = PagedMemoryCowFxHash::default();
+        mem.store_u64(0x1000, 1);
+        mem.store_u64(0x2000, 2);
 
-            let chunk = bytes_left.min(PAGE_SIZE - offset);
+        let snapshot = mem.snapshot();
+        assert_eq!(snapshot.page_count(), 2);
 
-            let page = self.ensure_page(idx);
-            page[offset..(offset + chunk)].copy_from_slice(&bytes[src_off..(src_off + chunk)]);
+        mem.store_u64(0x1000, 10);
+        mem.store_u64(0x3000, 30);
+        assert_eq!(mem.load_u64(0x1000), 10);
 
-            curr_addr += chunk as u64;
-            src_off += chunk;
-            bytes_left -= chunk;
-        }
+        mem.restore(&snapshot);
+        assert_eq!(mem.load_u64(0x1000), 1);
+        assert_eq!(mem.load_u64(0x2000), 2);
+        assert_eq!(mem.load_u64(0x3000), 0);
+
+        // the snapshot is not touched by writes after a restore
+        mem.store_u64(0x2000, 20);
+        mem.restore(&snapshot);
+        assert_eq!(mem.load_u64(0x2000), 2);
+    }
+
+    #[test]
+    fn forks_do_not_see_each_others_writes() {
+        let mut parent = PagedMemoryCowFxHash::default();
+        parent.store_u64(0x1000, 1);
+        parent.store_u64(0x2000, 2);
+
+        let mut child = parent.fork();
+        let mut grandchild = child.fork();
+        child.store_u64(0x1000, 10);
+        parent.store_u64(0x2000, 20);
+        grandchild.store_u64(0x3000, 30);
+
+        assert_eq!(parent.load_u64(0x1000), 1);
+        assert_eq!(child.load_u64(0x1000), 10);
+        assert_eq!(grandchild.load_u64(0x1000), 1);
+
+        assert_eq!(parent.load_u64(0x2000), 20);
+        assert_eq!(child.load_u64(0x2000), 2);
+        assert_eq!(grandchild.load_u64(0x2000), 2);
+
+        assert_eq!(parent.load_u64(0x3000), 0);
+        assert_eq!(child.load_u64(0x3000), 0);
+        assert_eq!(grandchild.load_u64(0x3000), 30);
+
+        // neither fork wrote to page 2, so they still share it
+        assert!(std::sync::Arc::ptr_eq(
+            &child.pages[&2],
+            &grandchild.pages[&2]
+        ));
+        assert!(!std::sync::Arc::ptr_eq(&parent.pages[&1], &child.pages[&1]));
+    }
+
+    #[test]
+    fn untouched_pages_stay_shared() {
+        let mut mem = PagedMemoryCowFxHash::default();
+        mem.store_u8(0x1000, 1);
+        mem.store_u8(0x2000, 2);
+
+        let snapshot = mem.snapshot();
+        mem.store_u8(0x1000, 3);
+
+        let shared = |idx| std::sync::Arc::ptr_eq(&mem.pages[&idx], &snapshot.pages[&idx]);
+        assert!(!shared(1));
+        assert!(shared(2));
+    }
+
+    #[test]
+    fn memory_and_snapshots_move_across_threads() {
+        let mut mem = PagedMemoryCowFxHash::default();
+        mem.store_u64(0x1000, 1);
+        let snapshot = mem.snapshot();
+
+        let loaded = std::thread::spawn(move || {
+            mem.store_u64(0x1000, 2);
+            mem.restore(&snapshot);
+            mem.load_u64(0x1000)
+        });
+        assert_eq!(loaded.join().unwrap(), 1);
     }
 }
@@ -1,31 +1,45 @@
-use std::{collections::HashMap, ptr::NonNull};
+use std::{collections::HashMap, io, path::Path, ptr::NonNull, slice, sync::Arc};
 
 use crate::{
     MemoryEmulator,
+    emulators::{
+        PAGE_SHIFT_2M, PAGE_SHIFT_4K, PAGE_SHIFT_64K, page_chunks, page_size_label,
+        paged::{PageBuf, Snapshot},
+    },
+    memory_image::{self, ImageHeader},
     named_hasher::{AHash, FxHash, NamedHasher, NoHashU64, Sip},
+    segments::{PageContents, SegmentPages},
 };
 
-/// Number of bits to describe entries in a page
-const PAGE_SHIFT: u64 = 12;
-/// Total number of entries in a page
-const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
-/// Mask to get the last `PAGE_SHIFT` bits of an address
-const PAGE_MASK: u64 = (PAGE_SIZE as u64) - 1;
-/// Max memory address
-const MAX_ADDR: u64 = u64::MAX;
-
-type Page = Box<[u8; PAGE_SIZE]>;
-
 pub type PagedMemoryCacheLastDefault = PagedMemoryCacheLast<Sip>;
 pub type PagedMemoryCacheLastAHash = PagedMemoryCacheLast<AHash>;
 pub type PagedMemoryCacheLastFxHash = PagedMemoryCacheLast<FxHash>;
 pub type PagedMemoryCacheLastNoHashU64 = PagedMemoryCacheLast<NoHashU64>;
 
+pub type PagedMemoryCacheLast4K<S> = PagedMemoryCacheLast<S, PAGE_SHIFT_4K>;
+pub type PagedMemoryCacheLast64K<S> = PagedMemoryCacheLast<S, PAGE_SHIFT_64K>;
+pub type PagedMemoryCacheLast2M<S> = PagedMemoryCacheLast<S, PAGE_SHIFT_2M>;
+
+/// Copy-on-write pages, see `PagedMemoryCow`
+pub type PagedMemoryCacheLastCow<S, const PAGE_SHIFT: u32 = PAGE_SHIFT_4K> =
+    PagedMemoryCacheLast<S, PAGE_SHIFT, Arc<[u8]>>;
+pub type PagedMemoryCacheLastCowFxHash = PagedMemoryCacheLastCow<FxHash>;
+
+/// `PAGE_SHIFT` is the number of bits to describe entries in a page
+/// `B` stores every page, see `PageBuf`
 #[derive(Default)]
-pub struct PagedMemoryCacheLast<S: NamedHasher> {
-    pages: HashMap<u64, Page, S>,
+pub struct PagedMemoryCacheLast<
+    S: NamedHasher,
+    const PAGE_SHIFT: u32 = PAGE_SHIFT_4K,
+    B: PageBuf = Box<[u8]>,
+> {
+    pages: HashMap<u64, B, S>,
     last_page_id: Option<u64>,
-    last_page_ptr: Option<NonNull<[u8; PAGE_SIZE]>>,
+    /// Start of the last page, which is always `PAGE_SIZE` bytes long
+    /// only pages owned by this memory alone are cached, so writing through it is sound,
+    /// `snapshot` shares every page and `restore` replaces them, both invalidate it,
+    /// as does `share_page` for the page it shares
+    last_page_ptr: Option<NonNull<u8>>,
 
     #[cfg(feature = "cache_stats")]
     cache_hit: u64,
@@ -33,9 +47,16 @@ pub struct PagedMemoryCacheLast<S: NamedHasher> {
     cache_miss: u64,
 }
 
-impl<S: NamedHasher> MemoryEmulator for PagedMemoryCacheLast<S> {
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> MemoryEmulator
+    for PagedMemoryCacheLast<S, PAGE_SHIFT, B>
+{
     fn name(&self) -> String {
-        format!("PagedMemCacheLast({})", S::NAME)
+        format!(
+            "PagedMemCacheLast{}{}({})",
+            B::LABEL,
+            page_size_label(PAGE_SHIFT),
+            S::NAME
+        )
     }
 
     fn load_u64(&mut self, addr: u64) -> u64 {
@@ -58,19 +79,31 @@ impl<S: NamedHasher> MemoryEmulator for PagedMemoryCacheLast<S> {
     }
 
     fn store_u64(&mut self, addr: u64, value: u64) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u32(&mut self, addr: u64, value: u32) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u16(&mut self, addr: u64, value: u16) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
     }
 
     fn store_u8(&mut self, addr: u64, value: u8) {
-        self.write_n_bytes(addr, &value.to_le_bytes());
+        self.write_n_bytes_const(addr, value.to_le_bytes());
+    }
+
+    fn load_bytes(&mut self, addr: u64, out: &mut [u8]) {
+        self.read_into(addr, out);
+    }
+
+    fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        self.write_n_bytes(addr, bytes);
+    }
+
+    fn fill(&mut self, addr: u64, len: usize, byte: u8) {
+        self.fill_n_bytes(addr, len, byte);
     }
 
     fn finish(&self) {
@@ -84,7 +117,26 @@ impl<S: NamedHasher> MemoryEmulator for PagedMemoryCacheLast<S> {
     }
 }
 
-impl<S: NamedHasher> PagedMemoryCacheLast<S> {
+impl<S: NamedHasher, const PAGE_SHIFT: u32> SegmentPages
+    for PagedMemoryCacheLastCow<S, PAGE_SHIFT>
+{
+    const PAGE_SHIFT: u32 = PAGE_SHIFT;
+
+    /// The shared page can no longer be written in place, so it is dropped from the cache
+    fn share_page(&mut self, idx: u64) -> Option<PageContents> {
+        if self.last_page_id == Some(idx) {
+            self.invalidate_cache();
+        }
+        self.pages.get(&idx).cloned().map(PageContents::new)
+    }
+}
+
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> PagedMemoryCacheLast<S, PAGE_SHIFT, B> {
+    /// Total number of entries in a page
+    pub const PAGE_SIZE: usize = 1 << PAGE_SHIFT;
+    /// Mask to get the last `PAGE_SHIFT` bits of an address
+    const PAGE_MASK: u64 = (Self::PAGE_SIZE as u64) - 1;
+
     /// Return the page index given the address
     #[inline]
     pub fn page_idx(addr: u64) -> u64 {
@@ -96,24 +148,49 @@ impl<S: NamedHasher> PagedMemoryCacheLast<S> {
     /// given an address
     #[inline]
     pub fn page_offset(addr: u64) -> usize {
-        (addr & PAGE_MASK) as usize
+        (addr & Self::PAGE_MASK) as usize
     }
 
+    #[inline]
     pub(crate) fn read_n_bytes_const<const N: usize>(&mut self, addr: u64) -> [u8; N] {
+        let offset = Self::page_offset(addr);
+        // fast path, the access lies within one page
+        if offset + N <= Self::PAGE_SIZE {
+            return match self.page_ptr(Self::page_idx(addr)) {
+                Some(page) => unsafe {
+                    (page.as_ptr().add(offset) as *const [u8; N]).read_unaligned()
+                },
+                None => [0; N],
+            };
+        }
+
         let mut out = [0u8; N];
         self.read_into(addr, &mut out);
         out
     }
 
-    fn page_ptr_mut(&mut self, page_id: u64) -> &mut [u8; PAGE_SIZE] {
-        if self.last_page_id == Some(page_id) {
-            if let Some(mut ptr) = self.last_page_ptr {
-                #[cfg(feature = "cache_stats")]
-                {
-                    self.cache_hit += 1
-                }
-                return unsafe { ptr.as_mut() };
+    #[inline]
+    fn write_n_bytes_const<const N: usize>(&mut self, addr: u64, bytes: [u8; N]) {
+        let offset = Self::page_offset(addr);
+        // fast path, the access lies within one page
+        if offset + N <= Self::PAGE_SIZE {
+            let page = self.page_ptr_mut(Self::page_idx(addr));
+            unsafe { (page.as_mut_ptr().add(offset) as *mut [u8; N]).write_unaligned(bytes) };
+            return;
+        }
+
+        self.write_n_bytes(addr, &bytes);
+    }
+
+    fn page_ptr_mut(&mut self, page_id: u64) -> &mut [u8] {
+        if self.last_page_id == Some(page_id)
+            && let Some(ptr) = self.last_page_ptr
+        {
+            #[cfg(feature = "cache_stats")]
+            {
+                self.cache_hit += 1
             }
+            return unsafe { slice::from_raw_parts_mut(ptr.as_ptr(), Self::PAGE_SIZE) };
         }
 
         #[cfg(feature = "cache_stats")]
@@ -121,26 +198,28 @@ impl<S: NamedHasher> PagedMemoryCacheLast<S> {
             self.cache_miss += 1;
         }
 
-        let entry = self
+        // copy the page if a snapshot shares it
+        let page = self
             .pages
             .entry(page_id)
-            .or_insert_with(|| Box::new([0; PAGE_SIZE]));
-        let ptr = NonNull::from(entry.as_mut());
+            .or_insert_with(|| B::zeroed(Self::PAGE_SIZE))
+            .make_mut();
+        let ptr = NonNull::from(&mut *page).cast();
 
         self.last_page_id = Some(page_id);
         self.last_page_ptr = Some(ptr);
-        entry
+        page
     }
 
-    fn page_ptr(&mut self, page_id: u64) -> Option<&[u8; PAGE_SIZE]> {
-        if self.last_page_id == Some(page_id) {
-            if let Some(ptr) = self.last_page_ptr {
-                #[cfg(feature = "cache_stats")]
-                {
-                    self.cache_hit += 1
-                }
-                return Some(unsafe { ptr.as_ref() });
+    fn page_ptr(&mut self, page_id: u64) -> Option<&[u8]> {
+        if self.last_page_id == Some(page_id)
+            && let Some(ptr) = self.last_page_ptr
+        {
+            #[cfg(feature = "cache_stats")]
+            {
+                self.cache_hit += 1
             }
+            return Some(unsafe { slice::from_raw_parts(ptr.as_ptr(), Self::PAGE_SIZE) });
         }
 
         #[cfg(feature = "cache_stats")]
@@ -151,85 +230,184 @@ impl<S: NamedHasher> PagedMemoryCacheLast<S> {
         let page = self
             .pages
             .entry(page_id)
-            .or_insert_with(|| Box::new([0; PAGE_SIZE]));
-        let ptr = NonNull::from(page.as_ref());
-
-        self.last_page_id = Some(page_id);
-        self.last_page_ptr = Some(ptr);
+            .or_insert_with(|| B::zeroed(Self::PAGE_SIZE));
+        // a page shared with a snapshot is read in place, but not cached for writing
+        if let Some(owned) = page.get_mut() {
+            self.last_page_id = Some(page_id);
+            self.last_page_ptr = Some(NonNull::from(owned).cast());
+        }
         Some(page)
     }
 
-    fn read_into(&mut self, addr: u64, out: &mut [u8]) {
-        let len = out.len();
-        if len == 0 {
-            return;
-        }
+    /// Forget the cached page
+    #[inline]
+    fn invalidate_cache(&mut self) {
+        self.last_page_id = None;
+        self.last_page_ptr = None;
+    }
+}
 
-        let _ = addr
-            .checked_add(len as u64 - 1)
-            .unwrap_or_else(|| panic!("read out of range: 0x{:x}", addr));
+impl<S: NamedHasher + Clone, const PAGE_SHIFT: u32> PagedMemoryCacheLastCow<S, PAGE_SHIFT> {
+    /// Capture the current contents of memory
+    /// the cached page becomes shared, so the cache is invalidated
+    pub fn snapshot(&mut self) -> Snapshot<S, PAGE_SHIFT> {
+        self.invalidate_cache();
+        Snapshot {
+            pages: self.pages.clone(),
+        }
+    }
 
-        let mut curr_addr = addr;
-        let mut bytes_left = len;
-        let mut dst_off = 0;
+    /// Rewind memory to a snapshot
+    /// the cached page may be dropped, so the cache is invalidated
+    pub fn restore(&mut self, snapshot: &Snapshot<S, PAGE_SHIFT>) {
+        self.invalidate_cache();
+        self.pages = snapshot.pages.clone();
+    }
 
-        while bytes_left > 0 {
-            let idx = Self::page_idx(curr_addr);
-            let offset = Self::page_offset(curr_addr);
+    /// A new memory with the same contents, sharing every page with this one
+    /// the cached page becomes shared, so the cache is invalidated
+    pub fn fork(&mut self) -> Self {
+        self.invalidate_cache();
+        Self {
+            pages: self.pages.clone(),
+            last_page_id: None,
+            last_page_ptr: None,
+
+            #[cfg(feature = "cache_stats")]
+            cache_hit: 0,
+            #[cfg(feature = "cache_stats")]
+            cache_miss: 0,
+        }
+    }
+}
 
-            let chunk = bytes_left.min(PAGE_SIZE - offset);
+impl<S: NamedHasher, const PAGE_SHIFT: u32, B: PageBuf> PagedMemoryCacheLast<S, PAGE_SHIFT, B> {
+    /// Every allocated page with its index, in no particular order
+    pub fn pages(&self) -> impl Iterator<Item = (u64, &[u8])> {
+        self.pages.iter().map(|(idx, page)| (*idx, &page[..]))
+    }
 
-            if let Some(page) = self.page_ptr(idx) {
-                out[dst_off..dst_off + chunk].copy_from_slice(&page[offset..offset + chunk]);
-            } // else leave as zeros
+    /// Write the contents of memory to a file, see `memory_image::write_image`
+    pub fn save_image<P: AsRef<Path>>(&self, path: P) -> io::Result<ImageHeader> {
+        memory_image::save_image(path, PAGE_SHIFT, self.pages())
+    }
 
-            curr_addr += chunk as u64;
-            dst_off += chunk;
-            bytes_left -= chunk;
+    /// Read n contiguous bytes from memory
+    fn read_into(&mut self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT, "read") {
+            match self.page_ptr(chunk.idx) {
+                Some(page) => out[chunk.buf].copy_from_slice(&page[chunk.page]),
+                None => out[chunk.buf].fill(0),
+            }
         }
     }
 
     fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
-        if bytes.is_empty() {
-            return;
-        }
-
-        let _ = addr
-            .checked_add(bytes.len() as u64 - 1)
-            .unwrap_or_else(|| panic!("write out of range: 0x{:x}", addr));
-
-        let mut curr_addr = addr;
-        let mut bytes_left = bytes.len();
-        let mut src_off = 0;
-
-        while bytes_left > 0 {
-            let idx = Self::page_idx(curr_addr);
-            let offset = Self::page_offset(curr_addr);
-
-            let chunk = bytes_left.min(PAGE_SIZE - offset);
+        self.write_chunks(addr, bytes.len(), |src_off, dst| {
+            dst.copy_from_slice(&bytes[src_off..src_off + dst.len()])
+        });
+    }
 
-            let page = self.page_ptr_mut(idx);
-            page[offset..offset + chunk].copy_from_slice(&bytes[src_off..src_off + chunk]);
+    /// Fill len contiguous bytes of memory with a byte
+    fn fill_n_bytes(&mut self, addr: u64, len: usize, byte: u8) {
+        self.write_chunks(addr, len, |_, dst| dst.fill(byte));
+    }
 
-            curr_addr += chunk as u64;
-            src_off += chunk;
-            bytes_left -= chunk;
+    /// Hand every page chunk of `len` bytes at `addr` to `f`
+    /// along with the offset of the chunk within the range, lazy allocates the pages
+    #[inline]
+    fn write_chunks(&mut self, addr: u64, len: usize, mut f: impl FnMut(usize, &mut [u8])) {
+        for chunk in page_chunks(addr, len, PAGE_SHIFT, "write") {
+            let page = self.page_ptr_mut(chunk.idx);
+            f(chunk.buf.start, &mut page[chunk.page]);
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::MemoryEmulator;
     use crate::emulators::paged_last_cache::{
-        PAGE_SIZE, PagedMemoryCacheLast, PagedMemoryCacheLastDefault,
+        PagedMemoryCacheLast2M, PagedMemoryCacheLastCowFxHash, PagedMemoryCacheLastDefault,
     };
+    use crate::named_hasher::FxHash;
 
     #[test]
     fn page_reuse_result_in_same_pointer() {
         let mut mem = PagedMemoryCacheLastDefault::default();
-        let p1 = mem.page_ptr_mut(5) as *mut [u8; PAGE_SIZE];
-        let p2 = mem.page_ptr_mut(5) as *mut [u8; PAGE_SIZE];
+        let p1 = mem.page_ptr_mut(5) as *mut [u8];
+        let p2 = mem.page_ptr_mut(5) as *mut [u8];
 
         assert_eq!(p1, p2);
     }
+
+    #[test]
+    fn cached_page_keeps_its_length() {
+        let mut mem = PagedMemoryCacheLast2M::<FxHash>::default();
+        assert_eq!(mem.page_ptr_mut(5).len(), 1 << 21);
+        // the second lookup is served from the cached pointer
+        assert_eq!(mem.page_ptr_mut(5).len(), 1 << 21);
+    }
+
+    #[test]
+    fn writes_through_the_cache_do_not_leak_into_snapshots() {
+        let mut mem = PagedMemoryCacheLastCowFxHash::default();
+        mem.store_u64(0x1000, 1);
+        assert_eq!(mem.last_page_id, Some(1));
+
+        let snapshot = mem.snapshot();
+        assert!(mem.last_page_ptr.is_none());
+
+        // a read of the shared page must not make it writable through the cache
+        assert_eq!(mem.load_u64(0x1000), 1);
+        assert!(mem.last_page_ptr.is_none());
+        mem.store_u64(0x1000, 2);
+        mem.store_u64(0x1008, 3);
+        assert_eq!(mem.load_u64(0x1000), 2);
+
+        mem.restore(&snapshot);
+        assert!(mem.last_page_ptr.is_none());
+        assert_eq!(mem.load_u64(0x1000), 1);
+        assert_eq!(mem.load_u64(0x1008), 0);
+
+        // replaying the section again gives the same result
+        mem.store_u64(0x1000, 2);
+        assert_eq!(mem.load_u64(0x1000), 2);
+        mem.restore(&snapshot);
+        assert_eq!(mem.load_u64(0x1000), 1);
+    }
+
+    #[test]
+    fn writes_through_the_cache_do_not_leak_into_forks() {
+        let mut parent = PagedMemoryCacheLastCowFxHash::default();
+        parent.store_u64(0x1000, 1);
+
+        let mut child = parent.fork();
+        assert!(parent.last_page_ptr.is_none());
+
+        // both sides write to the page that was cached in the parent
+        parent.store_u64(0x1000, 2);
+        child.store_u64(0x1008, 3);
+        assert_eq!(parent.load_u64(0x1000), 2);
+        assert_eq!(parent.load_u64(0x1008), 0);
+        assert_eq!(child.load_u64(0x1000), 1);
+        assert_eq!(child.load_u64(0x1008), 3);
+    }
+
+    #[test]
+    fn accesses_straddling_the_cached_page() {
+        let mut mem = PagedMemoryCacheLastDefault::default();
+        mem.write_n_bytes_const(0x1FFC, 0x0123_4567_89AB_CDEFu64.to_le_bytes());
+        mem.write_n_bytes_const(0x1FF8, 0xAABB_CCDDu32.to_le_bytes());
+
+        // page 0x2 was only written by the straddling slow path
+        assert_eq!(
+            mem.read_n_bytes_const::<4>(0x2000),
+            [0x67, 0x45, 0x23, 0x01]
+        );
+        assert_eq!(
+            mem.read_n_bytes_const::<8>(0x1FF8),
+            [0xDD, 0xCC, 0xBB, 0xAA, 0xEF, 0xCD, 0xAB, 0x89]
+        );
+    }
 }
@@ -0,0 +1,230 @@
+use std::{collections::HashMap, ptr::NonNull};
+
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, PAGE_SIZE_4K, page_chunks},
+    named_hasher::{AHash, FxHash, NamedHasher, NoHashU64, Sip},
+};
+
+type Page = Box<[u8; PAGE_SIZE_4K]>;
+
+pub type PagedMemorySplitCacheDefault = PagedMemorySplitCache<Sip>;
+pub type PagedMemorySplitCacheAHash = PagedMemorySplitCache<AHash>;
+pub type PagedMemorySplitCacheFxHash = PagedMemorySplitCache<FxHash>;
+pub type PagedMemorySplitCacheNoHashU64 = PagedMemorySplitCache<NoHashU64>;
+
+/// The last page touched by one kind of access
+#[derive(Default)]
+struct LastPage {
+    page_id: Option<u64>,
+    page_ptr: Option<NonNull<[u8; PAGE_SIZE_4K]>>,
+
+    #[cfg(feature = "cache_stats")]
+    cache_hit: u64,
+    #[cfg(feature = "cache_stats")]
+    cache_miss: u64,
+}
+
+impl LastPage {
+    #[inline]
+    fn get(&mut self, page_id: u64) -> Option<NonNull<[u8; PAGE_SIZE_4K]>> {
+        if self.page_id == Some(page_id)
+            && let Some(ptr) = self.page_ptr
+        {
+            #[cfg(feature = "cache_stats")]
+            {
+                self.cache_hit += 1
+            }
+            return Some(ptr);
+        }
+
+        #[cfg(feature = "cache_stats")]
+        {
+            self.cache_miss += 1;
+        }
+        None
+    }
+
+    #[inline]
+    fn set(&mut self, page_id: u64, ptr: NonNull<[u8; PAGE_SIZE_4K]>) {
+        self.page_id = Some(page_id);
+        self.page_ptr = Some(ptr);
+    }
+
+    #[cfg(feature = "cache_stats")]
+    fn report(&self, label: &str) {
+        let total = self.cache_hit + self.cache_miss;
+        let rate = if total > 0 {
+            self.cache_hit as f64 / total as f64 * 100.0
+        } else {
+            0.0
+        };
+        println!(
+            "{} cache hit: {}\n{} cache miss: {}\n{} hit rate: {:.2}%",
+            label, self.cache_hit, label, self.cache_miss, label, rate
+        );
+    }
+}
+
+/// Paged memory with independent last-page caches for loads and stores
+/// so reading one buffer while writing another doesn't miss on every access
+#[derive(Default)]
+pub struct PagedMemorySplitCache<S: NamedHasher> {
+    pages: HashMap<u64, Page, S>,
+    read_cache: LastPage,
+    write_cache: LastPage,
+}
+
+impl<S: NamedHasher> MemoryEmulator for PagedMemorySplitCache<S> {
+    fn name(&self) -> String {
+        format!("PagedMemSplitCache({})", S::NAME)
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        let bytes = self.read_n_bytes_const::<8>(addr);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        let bytes = self.read_n_bytes_const::<4>(addr);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        let bytes = self.read_n_bytes_const::<2>(addr);
+        u16::from_le_bytes(bytes)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read_n_bytes_const::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "cache_stats")]
+        {
+            self.read_cache.report("read");
+            self.write_cache.report("write");
+        }
+    }
+}
+
+impl<S: NamedHasher> PagedMemorySplitCache<S> {
+    /// Return the page index given the address
+    #[inline]
+    pub fn page_idx(addr: u64) -> u64 {
+        // addr = [PAGE_ID][PAGE_SHIFT]
+        addr >> PAGE_SHIFT_4K
+    }
+
+    /// Return the entry index within a page
+    /// given an address
+    #[inline]
+    pub fn page_offset(addr: u64) -> usize {
+        (addr & PAGE_MASK_4K) as usize
+    }
+
+    pub(crate) fn read_n_bytes_const<const N: usize>(&mut self, addr: u64) -> [u8; N] {
+        let mut out = [0u8; N];
+        self.read_into(addr, &mut out);
+        out
+    }
+
+    /// Pointer to a page, lazy allocating it
+    #[inline]
+    fn ensure_page(&mut self, page_id: u64) -> NonNull<[u8; PAGE_SIZE_4K]> {
+        let page = self
+            .pages
+            .entry(page_id)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE_4K]));
+        NonNull::from(page.as_mut())
+    }
+
+    fn page_ptr_mut(&mut self, page_id: u64) -> &mut [u8; PAGE_SIZE_4K] {
+        let mut ptr = match self.write_cache.get(page_id) {
+            Some(ptr) => ptr,
+            None => {
+                let ptr = self.ensure_page(page_id);
+                self.write_cache.set(page_id, ptr);
+                ptr
+            }
+        };
+        unsafe { ptr.as_mut() }
+    }
+
+    /// Missing pages are allocated on read too, so the next read hits
+    fn page_ptr(&mut self, page_id: u64) -> &[u8; PAGE_SIZE_4K] {
+        let ptr = match self.read_cache.get(page_id) {
+            Some(ptr) => ptr,
+            None => {
+                let ptr = self.ensure_page(page_id);
+                self.read_cache.set(page_id, ptr);
+                ptr
+            }
+        };
+        unsafe { ptr.as_ref() }
+    }
+
+    fn read_into(&mut self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT_4K, "read") {
+            let page = self.page_ptr(chunk.idx);
+            out[chunk.buf].copy_from_slice(&page[chunk.page]);
+        }
+    }
+
+    fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for chunk in page_chunks(addr, bytes.len(), PAGE_SHIFT_4K, "write") {
+            let page = self.page_ptr_mut(chunk.idx);
+            page[chunk.page].copy_from_slice(&bytes[chunk.buf]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MemoryEmulator,
+        emulators::{PAGE_SIZE_4K, paged_split_cache::PagedMemorySplitCacheDefault},
+    };
+
+    #[test]
+    fn read_and_write_caches_are_independent() {
+        let mut mem = PagedMemorySplitCacheDefault::default();
+
+        // copy from page 1 to page 2, both caches stay on their page
+        for i in 0..8u64 {
+            let value = mem.load_u8(0x1000 + i);
+            mem.store_u8(0x2000 + i, value + 1);
+        }
+        assert_eq!(mem.read_cache.page_id, Some(1));
+        assert_eq!(mem.write_cache.page_id, Some(2));
+
+        let write = mem.page_ptr_mut(2) as *mut [u8; PAGE_SIZE_4K] as *const [u8; PAGE_SIZE_4K];
+        let read = mem.page_ptr(2) as *const [u8; PAGE_SIZE_4K];
+        assert_eq!(write, read);
+        assert_eq!(mem.load_u64(0x2000), 0x0101_0101_0101_0101);
+    }
+
+    #[test]
+    fn last_bytes_of_memory() {
+        let mut mem = PagedMemorySplitCacheDefault::default();
+        mem.store_u16(u64::MAX - 1, 0xBEEF);
+        assert_eq!(mem.load_u16(u64::MAX - 1), 0xBEEF);
+        assert_eq!(mem.load_u8(u64::MAX), 0xBE);
+    }
+}
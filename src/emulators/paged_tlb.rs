@@ -0,0 +1,280 @@
+use std::{collections::HashMap, ptr::NonNull};
+
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, PAGE_SIZE_4K, page_chunks},
+    named_hasher::{FxHash, NamedHasher},
+};
+
+type Page = Box<[u8; PAGE_SIZE_4K]>;
+
+/// Named entries x ways, like the emulator names and registry keys
+pub type PagedMemoryTlb16x4 = PagedMemoryTlb<FxHash, 16, 4>;
+pub type PagedMemoryTlb64x4 = PagedMemoryTlb<FxHash, 64, 4>;
+pub type PagedMemoryTlb256x8 = PagedMemoryTlb<FxHash, 256, 8>;
+/// Fully associative, a single set holds every entry
+pub type PagedMemoryTlb8x8 = PagedMemoryTlb<FxHash, 8, 8>;
+
+#[derive(Clone, Copy, Default)]
+struct TlbEntry {
+    page_id: u64,
+    /// `None` for an empty way
+    ptr: Option<NonNull<[u8; PAGE_SIZE_4K]>>,
+    /// Tick of the last access, the smallest in a set is evicted first
+    last_used: u64,
+}
+
+/// Paged memory with an `ENTRIES` entry, `WAYS` way set associative TLB in front of the page map
+/// each set is replaced in least recently used order
+pub struct PagedMemoryTlb<S: NamedHasher, const ENTRIES: usize, const WAYS: usize> {
+    pages: HashMap<u64, Page, S>,
+    /// `ENTRIES / WAYS` sets of `WAYS` consecutive entries
+    tlb: Box<[TlbEntry]>,
+    tick: u64,
+
+    #[cfg(feature = "cache_stats")]
+    cache_hit: u64,
+    #[cfg(feature = "cache_stats")]
+    cache_miss: u64,
+    #[cfg(feature = "cache_stats")]
+    evictions: u64,
+}
+
+impl<S: NamedHasher + Default, const ENTRIES: usize, const WAYS: usize> Default
+    for PagedMemoryTlb<S, ENTRIES, WAYS>
+{
+    fn default() -> Self {
+        const {
+            assert!(
+                WAYS > 0 && ENTRIES.is_multiple_of(WAYS),
+                "entries must be a multiple of ways"
+            );
+            assert!(
+                (ENTRIES / WAYS).is_power_of_two(),
+                "set count must be a power of two"
+            );
+        }
+
+        Self {
+            pages: HashMap::default(),
+            tlb: vec![TlbEntry::default(); ENTRIES].into_boxed_slice(),
+            tick: 0,
+
+            #[cfg(feature = "cache_stats")]
+            cache_hit: 0,
+            #[cfg(feature = "cache_stats")]
+            cache_miss: 0,
+            #[cfg(feature = "cache_stats")]
+            evictions: 0,
+        }
+    }
+}
+
+impl<S: NamedHasher, const ENTRIES: usize, const WAYS: usize> MemoryEmulator
+    for PagedMemoryTlb<S, ENTRIES, WAYS>
+{
+    fn name(&self) -> String {
+        format!("PagedMemTlb{}x{}({})", ENTRIES, WAYS, S::NAME)
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        let bytes = self.read_n_bytes_const::<8>(addr);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        let bytes = self.read_n_bytes_const::<4>(addr);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        let bytes = self.read_n_bytes_const::<2>(addr);
+        u16::from_le_bytes(bytes)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read_n_bytes_const::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn finish(&self) {
+        #[cfg(feature = "cache_stats")]
+        println!(
+            "cache hit: {}\ncache miss: {}\nevictions: {}\ntotal: {}",
+            self.cache_hit,
+            self.cache_miss,
+            self.evictions,
+            self.cache_hit + self.cache_miss
+        );
+    }
+}
+
+impl<S: NamedHasher, const ENTRIES: usize, const WAYS: usize> PagedMemoryTlb<S, ENTRIES, WAYS> {
+    const SETS: usize = ENTRIES / WAYS;
+
+    /// Return the page index given the address
+    #[inline]
+    pub fn page_idx(addr: u64) -> u64 {
+        // addr = [PAGE_ID][PAGE_SHIFT]
+        addr >> PAGE_SHIFT_4K
+    }
+
+    /// Return the entry index within a page
+    /// given an address
+    #[inline]
+    pub fn page_offset(addr: u64) -> usize {
+        (addr & PAGE_MASK_4K) as usize
+    }
+
+    pub(crate) fn read_n_bytes_const<const N: usize>(&mut self, addr: u64) -> [u8; N] {
+        let mut out = [0u8; N];
+        self.read_into(addr, &mut out);
+        out
+    }
+
+    /// Entries of the set a page maps to
+    #[inline]
+    fn set(&mut self, page_id: u64) -> &mut [TlbEntry] {
+        let set = (page_id as usize) & (Self::SETS - 1);
+        &mut self.tlb[set * WAYS..(set + 1) * WAYS]
+    }
+
+    /// Returns the page, going through the TLB
+    /// lazy allocates the page on a miss, reads included, so the next access hits
+    #[inline]
+    fn page_ptr(&mut self, page_id: u64) -> &mut [u8; PAGE_SIZE_4K] {
+        self.tick += 1;
+        let tick = self.tick;
+
+        for entry in self.set(page_id) {
+            if entry.page_id == page_id
+                && let Some(mut ptr) = entry.ptr
+            {
+                entry.last_used = tick;
+                #[cfg(feature = "cache_stats")]
+                {
+                    self.cache_hit += 1;
+                }
+                return unsafe { ptr.as_mut() };
+            }
+        }
+
+        self.fill(page_id)
+    }
+
+    #[cold]
+    fn fill(&mut self, page_id: u64) -> &mut [u8; PAGE_SIZE_4K] {
+        #[cfg(feature = "cache_stats")]
+        {
+            self.cache_miss += 1;
+        }
+
+        let page = self
+            .pages
+            .entry(page_id)
+            .or_insert_with(|| Box::new([0; PAGE_SIZE_4K]));
+        let mut ptr = NonNull::from(page.as_mut());
+
+        let tick = self.tick;
+        let victim = self
+            .set(page_id)
+            .iter_mut()
+            .min_by_key(|e| (e.ptr.is_some(), e.last_used))
+            .unwrap();
+
+        #[cfg(feature = "cache_stats")]
+        let evicted = victim.ptr.is_some();
+
+        *victim = TlbEntry {
+            page_id,
+            ptr: Some(ptr),
+            last_used: tick,
+        };
+
+        #[cfg(feature = "cache_stats")]
+        if evicted {
+            self.evictions += 1;
+        }
+
+        unsafe { ptr.as_mut() }
+    }
+
+    fn read_into(&mut self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT_4K, "read") {
+            let page = self.page_ptr(chunk.idx);
+            out[chunk.buf].copy_from_slice(&page[chunk.page]);
+        }
+    }
+
+    fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for chunk in page_chunks(addr, bytes.len(), PAGE_SHIFT_4K, "write") {
+            let page = self.page_ptr(chunk.idx);
+            page[chunk.page].copy_from_slice(&bytes[chunk.buf]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MemoryEmulator,
+        emulators::{
+            PAGE_SIZE_4K,
+            paged_tlb::{PagedMemoryTlb, PagedMemoryTlb64x4},
+        },
+        named_hasher::FxHash,
+    };
+
+    #[test]
+    fn page_reuse_result_in_same_pointer() {
+        let mut mem = PagedMemoryTlb64x4::default();
+        let p1 = mem.page_ptr(5) as *mut [u8; PAGE_SIZE_4K];
+        mem.page_ptr(6);
+        let p2 = mem.page_ptr(5) as *mut [u8; PAGE_SIZE_4K];
+
+        assert_eq!(p1, p2);
+    }
+
+    #[test]
+    fn accesses_up_to_the_last_byte() {
+        let mut mem = PagedMemoryTlb64x4::default();
+        mem.store_u32(u64::MAX - 3, 0xDEAB_BEED);
+        assert_eq!(mem.load_u32(u64::MAX - 3), 0xDEAB_BEED);
+        assert_eq!(mem.load_u16(u64::MAX - 1), 0xDEAB);
+    }
+
+    #[test]
+    fn evicts_least_recently_used_way() {
+        // a single set of two ways
+        let mut mem = PagedMemoryTlb::<FxHash, 2, 2>::default();
+        let cached = |mem: &PagedMemoryTlb<FxHash, 2, 2>| {
+            let mut ids: Vec<_> = mem.tlb.iter().map(|e| e.page_id).collect();
+            ids.sort();
+            ids
+        };
+
+        mem.page_ptr(1);
+        mem.page_ptr(2);
+        mem.page_ptr(1);
+        mem.page_ptr(3);
+        assert_eq!(cached(&mem), vec![1, 3]);
+
+        mem.page_ptr(4);
+        assert_eq!(cached(&mem), vec![3, 4]);
+    }
+}
@@ -0,0 +1,204 @@
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, PAGE_SIZE_4K, page_chunks},
+};
+
+/// Number of page index bits resolved by each level of the tree
+/// 4 levels of 13 bits cover the 52-bit page index of 4 KiB pages
+const LEVEL_BITS: u64 = 13;
+/// Number of children of a node
+const FANOUT: usize = 1 << LEVEL_BITS;
+/// Mask to get the child index of a node from a shifted page index
+const LEVEL_MASK: u64 = (FANOUT as u64) - 1;
+
+type Page = Box<[u8; PAGE_SIZE_4K]>;
+
+/// A lazily populated table of children
+/// boxed, so `Option<Node<T>>` stays pointer sized
+struct Node<T>(Box<[Option<T>; FANOUT]>);
+
+impl<T> Default for Node<T> {
+    fn default() -> Self {
+        let children: Box<[Option<T>]> = std::iter::repeat_with(|| None).take(FANOUT).collect();
+        Node(children.try_into().ok().unwrap())
+    }
+}
+
+impl<T> Node<T> {
+    #[inline]
+    fn get(&self, idx: u64) -> Option<&T> {
+        self.0[idx as usize].as_ref()
+    }
+
+    #[inline]
+    fn get_or_insert_with(&mut self, idx: u64, f: impl FnOnce() -> T) -> &mut T {
+        self.0[idx as usize].get_or_insert_with(f)
+    }
+}
+
+/// Memory backed by a 4-level radix tree over the page index, like a hardware page table
+/// a page lookup is a fixed number of pointer hops with no hashing
+#[derive(Default)]
+pub struct RadixMemory {
+    root: Node<Node<Node<Node<Page>>>>,
+}
+
+impl MemoryEmulator for RadixMemory {
+    fn name(&self) -> String {
+        "RadixMem".to_string()
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        let bytes = self.read_n_bytes_const::<8>(addr);
+        u64::from_le_bytes(bytes)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        let bytes = self.read_n_bytes_const::<4>(addr);
+        u32::from_le_bytes(bytes)
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        let bytes = self.read_n_bytes_const::<2>(addr);
+        u16::from_le_bytes(bytes)
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read_n_bytes_const::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write_n_bytes(addr, &value.to_le_bytes());
+    }
+
+    fn finish(&self) {}
+}
+
+impl RadixMemory {
+    /// Return the page index given the address
+    #[inline]
+    pub fn page_idx(addr: u64) -> u64 {
+        // addr = [PAGE_ID][PAGE_SHIFT]
+        addr >> PAGE_SHIFT_4K
+    }
+
+    /// Return the entry index within a page
+    /// given an address
+    #[inline]
+    pub fn page_offset(addr: u64) -> usize {
+        (addr & PAGE_MASK_4K) as usize
+    }
+
+    /// Child index of a page index at each level, root first
+    #[inline]
+    fn level_indices(idx: u64) -> [u64; 4] {
+        [
+            (idx >> (3 * LEVEL_BITS)) & LEVEL_MASK,
+            (idx >> (2 * LEVEL_BITS)) & LEVEL_MASK,
+            (idx >> LEVEL_BITS) & LEVEL_MASK,
+            idx & LEVEL_MASK,
+        ]
+    }
+
+    #[inline]
+    fn page(&self, idx: u64) -> Option<&Page> {
+        let [l0, l1, l2, l3] = Self::level_indices(idx);
+        self.root.get(l0)?.get(l1)?.get(l2)?.get(l3)
+    }
+
+    /// Returns a mutable reference to a page given its index
+    /// lazy allocates the page and any missing interior nodes
+    #[inline]
+    fn ensure_page(&mut self, idx: u64) -> &mut Page {
+        let [l0, l1, l2, l3] = Self::level_indices(idx);
+        self.root
+            .get_or_insert_with(l0, Node::default)
+            .get_or_insert_with(l1, Node::default)
+            .get_or_insert_with(l2, Node::default)
+            .get_or_insert_with(l3, || Box::new([0; PAGE_SIZE_4K]))
+    }
+
+    pub(crate) fn read_n_bytes_const<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let mut out = [0u8; N];
+        self.read_into(addr, &mut out);
+        out
+    }
+
+    /// Read n contiguous bytes from memory
+    /// overwrites all of `out`, bytes of unallocated pages read as zero
+    fn read_into(&self, addr: u64, out: &mut [u8]) {
+        for chunk in page_chunks(addr, out.len(), PAGE_SHIFT_4K, "read") {
+            match self.page(chunk.idx) {
+                Some(page) => out[chunk.buf].copy_from_slice(&page[chunk.page]),
+                None => out[chunk.buf].fill(0),
+            }
+        }
+    }
+
+    /// Write n contiguous bytes into memory
+    /// Handles cross page writing
+    pub(crate) fn write_n_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        for chunk in page_chunks(addr, bytes.len(), PAGE_SHIFT_4K, "write") {
+            let page = self.ensure_page(chunk.idx);
+            page[chunk.page].copy_from_slice(&bytes[chunk.buf]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{MemoryEmulator, emulators::radix::RadixMemory};
+
+    #[test]
+    fn level_indices_cover_the_page_index() {
+        let idx = RadixMemory::page_idx(u64::MAX);
+        assert_eq!(RadixMemory::level_indices(idx), [0x1FFF; 4]);
+
+        let idx = (1 << 39) | (2 << 26) | (3 << 13) | 4;
+        assert_eq!(RadixMemory::level_indices(idx), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn last_word_of_memory() {
+        // the chunk loop steps past u64::MAX after copying the last byte
+        let mut mem = RadixMemory::default();
+        mem.store_u64(u64::MAX - 7, 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u64(u64::MAX - 7), 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u8(u64::MAX), 0x01);
+    }
+
+    #[test]
+    fn reads_do_not_allocate() {
+        let mut mem = RadixMemory::default();
+        assert!(mem.page(0x1234).is_none());
+        assert_eq!(mem.read_n_bytes_const::<8>(0x1234 << 12), [0; 8]);
+        assert!(mem.page(0x1234).is_none());
+
+        mem.write_n_bytes(0x1234 << 12, &[1]);
+        assert!(mem.page(0x1234).is_some());
+        assert!(mem.page(0x1235).is_none());
+    }
+
+    #[test]
+    fn reads_overwrite_the_whole_buffer() {
+        let mut mem = RadixMemory::default();
+        mem.store_u8(0x1FFF, 7);
+
+        // straddles an allocated page and an unallocated one
+        let mut out = [0xAA; 4];
+        mem.read_into(0x1FFE, &mut out);
+        assert_eq!(out, [0, 7, 0, 0]);
+    }
+}
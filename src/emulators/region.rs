@@ -0,0 +1,537 @@
+use std::path::Path;
+
+use fxhash::FxHashMap;
+
+use crate::{
+    MemoryEmulator,
+    emulators::{PAGE_MASK_4K, PAGE_SHIFT_4K, paged::PagedMemoryFxHash},
+    replay_reader::{ReplayError, ReplayReader, map_trace},
+};
+
+/// Smallest growth of a region buffer
+const MIN_GROWTH: usize = 64 << 10;
+/// Farthest past the end of its buffer a store may land and still grow the region,
+/// stores beyond go to the fallback rather than allocating the whole gap,
+/// also the largest step a buffer grows by
+const MAX_GROWTH_WINDOW: u64 = 16 << 20;
+/// Untouched pages tolerated inside one learned region
+const MAX_GAP_PAGES: u64 = 256;
+/// Regions kept by `learn_layout` by default
+pub const DEFAULT_MAX_REGIONS: usize = 8;
+
+/// Direction a region buffer grows in from its anchor
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Growth {
+    /// Anchored at `first`, like code, data and heap
+    Up,
+    /// Anchored at `last`, like a stack
+    Down,
+}
+
+/// A dense range of guest addresses, `first..=last`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegionSpec {
+    pub first: u64,
+    pub last: u64,
+    pub growth: Growth,
+}
+
+impl RegionSpec {
+    pub fn new(first: u64, last: u64, growth: Growth) -> Self {
+        assert!(first <= last, "empty region 0x{:x}..=0x{:x}", first, last);
+        Self {
+            first,
+            last,
+            growth,
+        }
+    }
+
+    /// Whether `len` bytes at `addr` lie inside the region
+    #[inline]
+    fn contains(&self, addr: u64, len: usize) -> bool {
+        addr >= self.first && addr <= self.last && self.last - addr >= len as u64 - 1
+    }
+
+    /// Number of bytes in the region, saturated for a region spanning the whole address space
+    fn size(&self) -> usize {
+        (self.last - self.first)
+            .saturating_add(1)
+            .try_into()
+            .unwrap_or(usize::MAX)
+    }
+}
+
+/// Layout used when none is given: 4 GiB of code, data and heap from address 0
+/// and 4 GiB of stack below the top of the address space
+pub fn default_layout() -> Vec<RegionSpec> {
+    vec![
+        RegionSpec::new(0, (1 << 32) - 1, Growth::Up),
+        RegionSpec::new(0u64.wrapping_sub(1 << 32), u64::MAX, Growth::Down),
+    ]
+}
+
+/// Access counts of the pages a trace touches
+struct PageUse {
+    accesses: u64,
+    /// Index of the first operation touching the page
+    first_touch: u64,
+}
+
+/// Learn a region layout from the 4 KiB pages a trace touches
+/// touched pages less than `MAX_GAP_PAGES` apart form one region, the `max_regions`
+/// most accessed regions are kept, a region first touched in its upper half grows down
+pub fn learn_layout<P: AsRef<Path>>(
+    path: P,
+    max_regions: usize,
+) -> Result<Vec<RegionSpec>, ReplayError> {
+    let mmap = map_trace(path).map_err(ReplayError::io)?;
+
+    let mut pages: FxHashMap<u64, PageUse> = FxHashMap::default();
+    for (i, op) in ReplayReader::from_slice(&mmap).enumerate() {
+        let op = op?;
+        let last = op.addr.saturating_add(op.width.bytes() as u64 - 1);
+        for page in (op.addr >> PAGE_SHIFT_4K)..=(last >> PAGE_SHIFT_4K) {
+            let entry = pages.entry(page).or_insert(PageUse {
+                accesses: 0,
+                first_touch: i as u64,
+            });
+            entry.accesses += 1;
+        }
+    }
+
+    let mut touched: Vec<(u64, PageUse)> = pages.into_iter().collect();
+    touched.sort_unstable_by_key(|(page, _)| *page);
+
+    struct Cluster {
+        first_page: u64,
+        last_page: u64,
+        accesses: u64,
+        first_touch: (u64, u64),
+    }
+
+    let mut clusters: Vec<Cluster> = vec![];
+    for (page, usage) in touched {
+        match clusters.last_mut() {
+            Some(c) if page - c.last_page <= MAX_GAP_PAGES => {
+                c.last_page = page;
+                c.accesses += usage.accesses;
+                if usage.first_touch < c.first_touch.0 {
+                    c.first_touch = (usage.first_touch, page);
+                }
+            }
+            _ => clusters.push(Cluster {
+                first_page: page,
+                last_page: page,
+                accesses: usage.accesses,
+                first_touch: (usage.first_touch, page),
+            }),
+        }
+    }
+
+    clusters.sort_by_key(|c| std::cmp::Reverse(c.accesses));
+    clusters.truncate(max_regions);
+
+    Ok(clusters
+        .into_iter()
+        .map(|c| {
+            let first_touched = c.first_touch.1;
+            let growth = if first_touched - c.first_page > (c.last_page - c.first_page) / 2 {
+                Growth::Down
+            } else {
+                Growth::Up
+            };
+            let last = (c.last_page << PAGE_SHIFT_4K) | PAGE_MASK_4K;
+            RegionSpec::new(c.first_page << PAGE_SHIFT_4K, last, growth)
+        })
+        .collect())
+}
+
+/// A region and the contiguous buffer serving it
+/// the buffer covers the `data.len()` bytes next to the anchor of the region
+struct Region {
+    spec: RegionSpec,
+    data: Vec<u8>,
+}
+
+impl Region {
+    /// Index into `data` of `len` bytes at `addr`, if the buffer covers all of them
+    /// the access must lie inside the region
+    #[inline]
+    fn index(&self, addr: u64, len: usize) -> Option<usize> {
+        match self.spec.growth {
+            Growth::Up => {
+                let offset = addr - self.spec.first;
+                let covered = self.data.len() as u64;
+                (offset < covered && len as u64 <= covered - offset).then_some(offset as usize)
+            }
+            Growth::Down => {
+                let from_top = self.spec.last - addr;
+                (from_top < self.data.len() as u64).then(|| self.data.len() - 1 - from_top as usize)
+            }
+        }
+    }
+
+    /// First address covered by a buffer of `len` bytes
+    fn base(&self, len: usize) -> u64 {
+        match self.spec.growth {
+            Growth::Up => self.spec.first,
+            Growth::Down => self.spec.last - (len as u64 - 1),
+        }
+    }
+
+    /// Grow the buffer until it covers `len` bytes at `addr` and return their index
+    /// `None` if they lie more than `MAX_GROWTH_WINDOW` past the end of the buffer
+    #[inline]
+    fn index_or_grow(
+        &mut self,
+        addr: u64,
+        len: usize,
+        fallback: &PagedMemoryFxHash,
+    ) -> Option<usize> {
+        if let Some(i) = self.index(addr, len) {
+            return Some(i);
+        }
+
+        let needed = match self.spec.growth {
+            Growth::Up => addr - self.spec.first + len as u64,
+            Growth::Down => self.spec.last - addr + 1,
+        };
+        if needed - self.data.len() as u64 > MAX_GROWTH_WINDOW {
+            return None;
+        }
+        self.grow(needed as usize, fallback);
+        self.index(addr, len)
+    }
+
+    /// Grow the buffer to at least `needed` bytes, doubling to amortise the copies
+    /// but by at most `MAX_GROWTH_WINDOW` per step, so a large region is never allocated whole
+    /// bytes stored to the fallback while out of reach move into the new part of the buffer
+    #[cold]
+    fn grow(&mut self, needed: usize, fallback: &PagedMemoryFxHash) {
+        let old_len = self.data.len();
+        let step = old_len.min(MAX_GROWTH_WINDOW as usize);
+        let new_len = needed
+            .max(old_len + step)
+            .max(MIN_GROWTH)
+            .min(self.spec.size());
+
+        let (lo, hi) = match self.spec.growth {
+            Growth::Up => {
+                self.data.reserve_exact(new_len - old_len);
+                self.data.resize(new_len, 0);
+                (
+                    self.spec.first + old_len as u64,
+                    self.spec.first + (new_len as u64 - 1),
+                )
+            }
+            Growth::Down => {
+                // the anchor is the end of the buffer, keep the old bytes there
+                let mut data = vec![0; new_len];
+                data[new_len - old_len..].copy_from_slice(&self.data);
+                self.data = data;
+                (self.base(new_len), self.spec.last - old_len as u64)
+            }
+        };
+
+        // only the pages of the newly covered range can hold bytes of the fallback
+        let base = self.base(new_len);
+        let page_size = PagedMemoryFxHash::PAGE_SIZE as u64;
+        for idx in PagedMemoryFxHash::page_idx(lo)..=PagedMemoryFxHash::page_idx(hi) {
+            let Some(page) = fallback.page(idx) else {
+                continue;
+            };
+            let page_first = idx * page_size;
+            let first = page_first.max(lo);
+            let last = (page_first + (page_size - 1)).min(hi);
+            let src = (first - page_first) as usize..=(last - page_first) as usize;
+            let dst = (first - base) as usize;
+            self.data[dst..=dst + (last - first) as usize].copy_from_slice(&page[src]);
+        }
+    }
+}
+
+/// Memory serving a few dense regions from contiguous growable buffers
+/// the layout is given up front or learned from a trace,
+/// addresses outside every region fall back to a `PagedMemory`,
+/// as do stores out of reach of a region buffer, see `MAX_GROWTH_WINDOW`
+pub struct RegionMemory {
+    regions: Vec<Region>,
+    learned: bool,
+    fallback: PagedMemoryFxHash,
+}
+
+impl Default for RegionMemory {
+    fn default() -> Self {
+        Self::new(&default_layout())
+    }
+}
+
+impl RegionMemory {
+    /// Regions must not overlap, the first ones are looked up first
+    pub fn new(layout: &[RegionSpec]) -> Self {
+        for (i, a) in layout.iter().enumerate() {
+            for b in &layout[i + 1..] {
+                assert!(
+                    a.last < b.first || b.last < a.first,
+                    "overlapping regions {:x?} and {:x?}",
+                    a,
+                    b
+                );
+            }
+        }
+
+        Self {
+            regions: layout
+                .iter()
+                .map(|&spec| Region { spec, data: vec![] })
+                .collect(),
+            learned: false,
+            fallback: PagedMemoryFxHash::default(),
+        }
+    }
+
+    /// Memory laid out for a trace, see `learn_layout`
+    pub fn for_trace<P: AsRef<Path>>(path: P) -> Result<Self, ReplayError> {
+        let mut mem = Self::new(&learn_layout(path, DEFAULT_MAX_REGIONS)?);
+        mem.learned = true;
+        Ok(mem)
+    }
+
+    pub fn layout(&self) -> impl Iterator<Item = &RegionSpec> {
+        self.regions.iter().map(|r| &r.spec)
+    }
+
+    #[inline]
+    fn read<const N: usize>(&self, addr: u64) -> [u8; N] {
+        if let Some(region) = self.regions.iter().find(|r| r.spec.contains(addr, N))
+            && let Some(i) = region.index(addr, N)
+        {
+            return region.data[i..i + N].try_into().unwrap();
+        }
+        self.read_slow(addr)
+    }
+
+    #[inline]
+    fn write<const N: usize>(&mut self, addr: u64, bytes: [u8; N]) {
+        if let Some(region) = self.regions.iter_mut().find(|r| r.spec.contains(addr, N))
+            && let Some(i) = region.index_or_grow(addr, N, &self.fallback)
+        {
+            region.data[i..i + N].copy_from_slice(&bytes);
+            return;
+        }
+        self.write_slow(addr, &bytes);
+    }
+
+    /// Byte at a time for stray accesses, accesses straddling region edges
+    /// and accesses beyond the grown part of a region
+    #[cold]
+    fn read_slow<const N: usize>(&self, addr: u64) -> [u8; N] {
+        let _ = addr
+            .checked_add(N as u64 - 1)
+            .unwrap_or_else(|| panic!("read out of range: 0x{:x}", addr));
+
+        let mut out = [0u8; N];
+        for (i, byte) in out.iter_mut().enumerate() {
+            let addr = addr + i as u64;
+            let region = self.regions.iter().find(|r| r.spec.contains(addr, 1));
+            *byte = match region.and_then(|r| Some((r, r.index(addr, 1)?))) {
+                Some((region, i)) => region.data[i],
+                None => self.fallback.read_n_bytes_const::<1>(addr)[0],
+            };
+        }
+        out
+    }
+
+    #[cold]
+    fn write_slow(&mut self, addr: u64, bytes: &[u8]) {
+        let _ = addr
+            .checked_add(bytes.len() as u64 - 1)
+            .unwrap_or_else(|| panic!("write out of range: 0x{:x}", addr));
+
+        for (i, byte) in bytes.iter().enumerate() {
+            let addr = addr + i as u64;
+            if let Some(region) = self.regions.iter_mut().find(|r| r.spec.contains(addr, 1))
+                && let Some(i) = region.index_or_grow(addr, 1, &self.fallback)
+            {
+                region.data[i] = *byte;
+            } else {
+                self.fallback.write_n_bytes(addr, &[*byte]);
+            }
+        }
+    }
+}
+
+impl MemoryEmulator for RegionMemory {
+    fn name(&self) -> String {
+        if self.learned {
+            "RegionMem(learned)".to_string()
+        } else {
+            "RegionMem".to_string()
+        }
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        u64::from_le_bytes(self.read::<8>(addr))
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        u32::from_le_bytes(self.read::<4>(addr))
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        u16::from_le_bytes(self.read::<2>(addr))
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.read::<1>(addr)[0]
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.write(addr, value.to_le_bytes());
+    }
+
+    fn finish(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        MemoryEmulator,
+        emulators::region::{Growth, MAX_GROWTH_WINDOW, RegionMemory, RegionSpec, learn_layout},
+    };
+
+    #[test]
+    fn stack_grows_down_and_keeps_its_contents() {
+        let top = u64::MAX;
+        let mut mem = RegionMemory::new(&[RegionSpec::new(0, top, Growth::Down)]);
+
+        mem.store_u64(top - 7, 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.regions[0].data.len(), 64 << 10);
+
+        // far enough down to force a second growth
+        mem.store_u32(top - (1 << 20), 0xAABB_CCDD);
+        assert!(mem.regions[0].data.len() > 1 << 20);
+        assert_eq!(mem.load_u64(top - 7), 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u32(top - (1 << 20)), 0xAABB_CCDD);
+        assert_eq!(mem.load_u64(0x1000), 0);
+    }
+
+    #[test]
+    fn accesses_straddling_a_region_edge() {
+        let mut mem = RegionMemory::new(&[RegionSpec::new(0x1000, 0x1FFF, Growth::Up)]);
+
+        mem.store_u64(0x1FFC, 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u64(0x1FFC), 0x0123_4567_89AB_CDEF);
+        assert_eq!(mem.load_u32(0x2000), 0x0123_4567);
+        assert_eq!(
+            mem.fallback.read_n_bytes_const::<4>(0x2000),
+            [0x67, 0x45, 0x23, 0x01]
+        );
+
+        // reads never grow a region
+        assert_eq!(mem.load_u8(0xFFF), 0);
+        let mut mem = RegionMemory::new(&[RegionSpec::new(0x1000, 0x1FFF, Growth::Up)]);
+        assert_eq!(mem.load_u64(0x1800), 0);
+        assert!(mem.regions[0].data.is_empty());
+    }
+
+    #[test]
+    fn distant_stores_do_not_allocate_the_gap() {
+        let mut mem = RegionMemory::default();
+        mem.store_u64(0xFFFF_0000, 1);
+        assert_eq!(mem.regions[0].data.capacity(), 0);
+        assert_eq!(mem.fallback.pages().count(), 1);
+        assert_eq!(mem.load_u64(0xFFFF_0000), 1);
+
+        // within the window past the end of the buffer, which grows just enough
+        mem.store_u64(0x80_0000, 2);
+        assert_eq!(mem.regions[0].data.len(), 0x80_0008);
+        assert_eq!(mem.fallback.pages().count(), 1);
+    }
+
+    #[test]
+    fn growth_steps_are_capped() {
+        let mut mem = RegionMemory::default();
+        let window = MAX_GROWTH_WINDOW as usize;
+
+        // doubles while small, then grows by one window at a time
+        for len in [window, 2 * window, 3 * window] {
+            let end = mem.regions[0].data.len() as u64;
+            mem.store_u8(end + MAX_GROWTH_WINDOW - 1, 1);
+            assert_eq!(mem.regions[0].data.len(), len);
+            assert_eq!(mem.regions[0].data.capacity(), len);
+        }
+        assert_eq!(mem.load_u8(3 * MAX_GROWTH_WINDOW - 1), 1);
+    }
+
+    #[test]
+    fn growth_takes_over_bytes_held_by_the_fallback() {
+        let mut mem = RegionMemory::new(&[RegionSpec::new(0, u64::MAX, Growth::Down)]);
+        let far = u64::MAX - 3 * MAX_GROWTH_WINDOW;
+        mem.store_u32(far, 0xAABB_CCDD);
+        assert!(mem.regions[0].data.is_empty());
+
+        // walk the stack down within reach of the buffer until it covers `far`
+        let mut addr = u64::MAX - 7;
+        while mem.regions[0].index(far, 4).is_none() {
+            assert!(addr > far + 8);
+            mem.store_u64(addr, addr);
+            addr -= MAX_GROWTH_WINDOW / 2;
+        }
+        assert_eq!(mem.load_u32(far), 0xAABB_CCDD);
+
+        mem.store_u16(far, 0x1122);
+        assert_eq!(mem.load_u32(far), 0xAABB_1122);
+        assert_eq!(mem.load_u64(u64::MAX - 7), u64::MAX - 7);
+    }
+
+    #[test]
+    fn layout_learned_from_trace() {
+        let mut trace = vec![];
+        let mut push = |op: u8, addr: u64| {
+            trace.extend_from_slice(&[op, 8]);
+            trace.extend_from_slice(&addr.to_le_bytes());
+            if op == 1 {
+                trace.extend_from_slice(&0u64.to_le_bytes());
+            }
+        };
+        // a stack pushed from the top, a heap filled from its start and one stray access
+        for i in 0..800u64 {
+            push(1, u64::MAX - 7 - i * 8);
+        }
+        for i in 0..500u64 {
+            push(1, 0x10_0000 + i * 8);
+            push(2, 0x10_0000 + i * 8);
+        }
+        push(2, 0x7000_0000);
+
+        let path = std::env::temp_dir().join(format!("fast-mem-{}-region.bin", std::process::id()));
+        std::fs::write(&path, &trace).unwrap();
+
+        let layout = learn_layout(&path, 2).unwrap();
+        assert_eq!(
+            layout,
+            vec![
+                RegionSpec::new(0x10_0000, 0x10_0FFF, Growth::Up),
+                RegionSpec::new(u64::MAX - 0x1FFF, u64::MAX, Growth::Down),
+            ]
+        );
+
+        let mem = RegionMemory::for_trace(&path).unwrap();
+        assert_eq!(mem.layout().count(), 3);
+        assert_eq!(mem.name(), "RegionMem(learned)");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,295 @@
+use fxhash::FxHashMap;
+
+use crate::{
+    MemoryEmulator,
+    emulators::PAGE_SHIFT_4K,
+    replay_reader::{MemOp, OpKind, Width},
+};
+
+/// Number of bits to describe bytes in a word
+const WORD_SHIFT: u32 = 3;
+/// Mask clearing the byte offset within a word
+const WORD_MASK: u64 = !((1 << WORD_SHIFT) - 1);
+/// Number of words in a 4 KiB page
+/// a shadow page holds the timestamps of one page of data
+const WORDS_PER_PAGE: usize = 1 << (PAGE_SHIFT_4K - WORD_SHIFT);
+
+type ShadowPage = Box<[u64; WORDS_PER_PAGE]>;
+
+/// One word touched by one access, as needed by an offline memory-checking argument
+/// a load reads `prev_value` back, so `value == prev_value` for loads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimestampedAccess {
+    pub kind: OpKind,
+    /// Address of the 8-byte aligned word
+    pub addr: u64,
+    /// Word contents before the access, zero if never written
+    pub prev_value: u64,
+    /// Timestamp of the previous access to the word, zero if never accessed
+    pub prev_ts: u64,
+    /// Word contents after the access
+    pub value: u64,
+    /// Timestamp of this access, starting at one and increasing by one per access
+    pub ts: u64,
+}
+
+/// Memory wrapper tracking the last access time of every 8-byte word
+/// timestamps live in shadow pages parallel to the data pages,
+/// each access reports every word it touches to `sink`, two for an access straddling words
+pub struct TimestampedMemory<M: MemoryEmulator, F: FnMut(&TimestampedAccess)> {
+    inner: M,
+    sink: F,
+    shadow: FxHashMap<u64, ShadowPage>,
+    /// Timestamp of the last access
+    clock: u64,
+}
+
+impl<M: MemoryEmulator, F: FnMut(&TimestampedAccess)> TimestampedMemory<M, F> {
+    pub fn new(inner: M, sink: F) -> Self {
+        Self {
+            inner,
+            sink,
+            shadow: FxHashMap::default(),
+            clock: 0,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.inner
+    }
+
+    /// Timestamp of the last access, which is also the number of accesses so far
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// Timestamp of the last access to a word, zero if it was never accessed
+    pub fn last_access(&self, addr: u64) -> u64 {
+        let word = addr & WORD_MASK;
+        self.shadow
+            .get(&(word >> PAGE_SHIFT_4K))
+            .map_or(0, |page| page[Self::word_index(word)])
+    }
+
+    #[inline]
+    fn word_index(word: u64) -> usize {
+        ((word >> WORD_SHIFT) as usize) & (WORDS_PER_PAGE - 1)
+    }
+
+    /// Replace the timestamp of a word, returning the previous one
+    #[inline]
+    fn swap_ts(&mut self, word: u64, ts: u64) -> u64 {
+        let page = self
+            .shadow
+            .entry(word >> PAGE_SHIFT_4K)
+            .or_insert_with(|| Box::new([0; WORDS_PER_PAGE]));
+        std::mem::replace(&mut page[Self::word_index(word)], ts)
+    }
+
+    /// Apply an access to the inner memory and report the words it touches
+    /// returns the loaded value for loads
+    fn access(&mut self, op: MemOp) -> Option<u64> {
+        let last_byte = op
+            .addr
+            .checked_add(op.width.bytes() as u64 - 1)
+            .unwrap_or_else(|| panic!("access out of range: 0x{:x}", op.addr));
+        let first = op.addr & WORD_MASK;
+        let last = last_byte & WORD_MASK;
+
+        self.clock += 1;
+        let ts = self.clock;
+
+        let mut words = [(first, 0), (last, 0)];
+        let words = if first == last {
+            &mut words[..1]
+        } else {
+            &mut words[..]
+        };
+        for (word, prev_value) in words.iter_mut() {
+            *prev_value = self.inner.load_u64(*word);
+        }
+
+        let loaded = op.apply(&mut self.inner);
+
+        for &(word, prev_value) in words.iter() {
+            let value = match op.kind {
+                OpKind::Load => prev_value,
+                OpKind::Store => self.inner.load_u64(word),
+            };
+            let prev_ts = self.swap_ts(word, ts);
+            (self.sink)(&TimestampedAccess {
+                kind: op.kind,
+                addr: word,
+                prev_value,
+                prev_ts,
+                value,
+                ts,
+            });
+        }
+        loaded
+    }
+
+    #[inline]
+    fn load(&mut self, addr: u64, width: Width) -> u64 {
+        let op = MemOp {
+            kind: OpKind::Load,
+            width,
+            addr,
+            value: 0,
+        };
+        self.access(op).unwrap()
+    }
+
+    #[inline]
+    fn store(&mut self, addr: u64, width: Width, value: u64) {
+        let op = MemOp {
+            kind: OpKind::Store,
+            width,
+            addr,
+            value,
+        };
+        self.access(op);
+    }
+}
+
+impl<M: MemoryEmulator, F: FnMut(&TimestampedAccess)> MemoryEmulator for TimestampedMemory<M, F> {
+    fn name(&self) -> String {
+        format!("Timestamped({})", self.inner.name())
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        self.load(addr, Width::U64)
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        self.load(addr, Width::U32) as u32
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        self.load(addr, Width::U16) as u16
+    }
+
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.load(addr, Width::U8) as u8
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.store(addr, Width::U64, value);
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.store(addr, Width::U32, value as u64);
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.store(addr, Width::U16, value as u64);
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.store(addr, Width::U8, value as u64);
+    }
+
+    fn finish(&self) {
+        self.inner.finish();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use crate::{
+        MemoryEmulator,
+        emulators::{
+            paged::PagedMemoryFxHash,
+            timestamped::{TimestampedAccess, TimestampedMemory},
+        },
+        replay_reader::OpKind,
+    };
+
+    #[test]
+    fn accesses_report_previous_value_and_timestamp() {
+        let mut log = vec![];
+        let mut mem =
+            TimestampedMemory::new(PagedMemoryFxHash::default(), |a: &TimestampedAccess| {
+                log.push(*a)
+            });
+
+        mem.store_u32(0x1004, 0xAABB_CCDD);
+        assert_eq!(mem.load_u8(0x1005), 0xCC);
+        // straddles the words at 0x1000 and 0x1008
+        mem.store_u16(0x1007, 0x1122);
+        assert_eq!(mem.last_access(0x1008), 3);
+        assert_eq!(mem.clock(), 3);
+        drop(mem);
+
+        let word = |kind, addr, prev_value, prev_ts, value, ts| TimestampedAccess {
+            kind,
+            addr,
+            prev_value,
+            prev_ts,
+            value,
+            ts,
+        };
+        assert_eq!(
+            log,
+            [
+                word(OpKind::Store, 0x1000, 0, 0, 0xAABB_CCDD_0000_0000, 1),
+                word(
+                    OpKind::Load,
+                    0x1000,
+                    0xAABB_CCDD_0000_0000,
+                    1,
+                    0xAABB_CCDD_0000_0000,
+                    2
+                ),
+                word(
+                    OpKind::Store,
+                    0x1000,
+                    0xAABB_CCDD_0000_0000,
+                    2,
+                    0x22BB_CCDD_0000_0000,
+                    3
+                ),
+                word(OpKind::Store, 0x1008, 0, 0, 0x11, 3),
+            ]
+        );
+    }
+
+    #[test]
+    fn reads_and_writes_are_consistent() {
+        // the offline memory-checking invariant: every access continues
+        // from the value and timestamp the previous access to the word left behind
+        let mut last: HashMap<u64, (u64, u64)> = HashMap::new();
+        let mut violations = 0;
+        let mut mem =
+            TimestampedMemory::new(PagedMemoryFxHash::default(), |a: &TimestampedAccess| {
+                let (value, ts) = last.get(&a.addr).copied().unwrap_or((0, 0));
+                if (value, ts) != (a.prev_value, a.prev_ts) || a.prev_ts >= a.ts {
+                    violations += 1;
+                }
+                last.insert(a.addr, (a.value, a.ts));
+            });
+
+        let mut x = 0x2545_F491_4F6C_DD1Du64;
+        for _ in 0..10_000 {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            let addr = 0x1000 + (x >> 40) % 0x100;
+            match x % 5 {
+                0 => mem.store_u64(addr, x),
+                1 => mem.store_u32(addr, x as u32),
+                2 => mem.store_u8(addr, x as u8),
+                3 => {
+                    mem.load_u16(addr);
+                }
+                _ => {
+                    mem.load_u64(addr);
+                }
+            }
+        }
+        drop(mem);
+        assert_eq!(violations, 0);
+    }
+}
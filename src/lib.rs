@@ -1,9 +1,28 @@
-use std::fs::File;
-
-use memmap2::Mmap;
-
+pub mod bench;
+pub mod differential;
 pub mod emulators;
+pub mod memory_image;
 pub mod named_hasher;
+pub mod replay_reader;
+pub mod segments;
+pub mod sha256;
+#[cfg(test)]
+mod test_util;
+pub mod trace_format;
+pub mod verify;
+
+pub use differential::replay_differential;
+pub use segments::replay_segments;
+pub use verify::replay_and_verify;
+
+use std::{
+    fs::File,
+    io::{self, BufWriter},
+    path::Path,
+};
+
+use replay_reader::{MemOp, OpKind, ReplayError, ReplayErrorKind, ReplayReader, map_trace};
+use trace_format::{Checksum, TraceEncoding, TraceFormat, TraceHeader, TraceWriter};
 
 pub trait MemoryEmulator {
     fn load_u8(&mut self, addr: u64) -> u8;
@@ -16,10 +35,43 @@ pub trait MemoryEmulator {
     fn store_u32(&mut self, addr: u64, value: u32);
     fn store_u64(&mut self, addr: u64, value: u64);
 
+    /// Load `out.len()` contiguous bytes starting at `addr`
+    fn load_bytes(&mut self, addr: u64, out: &mut [u8]) {
+        check_range(addr, out.len(), "read");
+        for (i, byte) in out.iter_mut().enumerate() {
+            *byte = self.load_u8(addr + i as u64);
+        }
+    }
+
+    /// Store contiguous bytes starting at `addr`
+    fn store_bytes(&mut self, addr: u64, bytes: &[u8]) {
+        check_range(addr, bytes.len(), "write");
+        for (i, byte) in bytes.iter().enumerate() {
+            self.store_u8(addr + i as u64, *byte);
+        }
+    }
+
+    /// Set `len` contiguous bytes starting at `addr` to `byte`
+    fn fill(&mut self, addr: u64, len: usize, byte: u8) {
+        check_range(addr, len, "write");
+        for i in 0..len {
+            self.store_u8(addr + i as u64, byte);
+        }
+    }
+
     fn name(&self) -> String;
     fn finish(&self);
 }
 
+/// Panic like the backends do if `len` bytes at `addr` run past the top of the address space
+#[inline]
+fn check_range(addr: u64, len: usize, access: &str) {
+    if len > 0 && addr.checked_add(len as u64 - 1).is_none() {
+        panic!("{} out of range: 0x{:x}", access, addr);
+    }
+}
+
+#[cfg(test)]
 fn test_memory_emulator<M: MemoryEmulator>(mut mem: M) {
     let addrs: &[u64] = &[
         0,
@@ -62,95 +114,189 @@ fn test_memory_emulator<M: MemoryEmulator>(mut mem: M) {
     mem.store_u8(base + 2, 0x34);
     mem.store_u8(base + 3, 0x12);
     assert_eq!(mem.load_u32(base), 0x1234_5678);
+
+    // bulk accesses spanning several pages
+    let base = 0x7FF0;
+    let bytes: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+    mem.store_bytes(base, &bytes);
+    let mut out = vec![0xFF; bytes.len()];
+    mem.load_bytes(base, &mut out);
+    assert_eq!(out, bytes);
+    assert_eq!(mem.load_u32(base + 0x10), 0x1312_1110);
+
+    mem.fill(base + 0x0FF0, 0x20, 0xCC);
+    assert_eq!(mem.load_u64(base + 0x0FE8), 0xEFEE_EDEC_EBEA_E9E8);
+    assert_eq!(mem.load_u64(base + 0x0FF0), 0xCCCC_CCCC_CCCC_CCCC);
+    assert_eq!(mem.load_u64(base + 0x1008), 0xCCCC_CCCC_CCCC_CCCC);
+    assert_eq!(mem.load_u8(base + 0x1010), 0x10);
+
+    // untouched memory reads as zero whatever the buffer held
+    let mut out = [0xFF; 0x2000];
+    mem.load_bytes(0x40_0000, &mut out);
+    assert!(out.iter().all(|&b| b == 0));
 }
 
-struct ReplayIter<'a> {
-    data: &'a [u8],
+/// Summary of a completed replay
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ReplayStats {
+    pub records: u64,
+    pub loads: u64,
+    pub stores: u64,
 }
 
-impl<'a> ReplayIter<'a> {
+impl ReplayStats {
     #[inline]
-    fn take(&mut self, n: usize) -> Option<&'a [u8]> {
-        if self.data.len() < n {
-            return None;
+    fn record(&mut self, kind: OpKind) {
+        self.records += 1;
+        match kind {
+            OpKind::Load => self.loads += 1,
+            OpKind::Store => self.stores += 1,
         }
-
-        let (head, tail) = self.data.split_at(n);
-        self.data = tail;
-        Some(head)
     }
 }
 
-pub fn replay_mem_operations<M: MemoryEmulator>(file_path: &'static str, mem_emulator: &mut M) {
-    let file = File::open(file_path).unwrap();
-    let mmap = unsafe { Mmap::map(&file).expect("mmap failed") };
+/// Replay every operation of a trace file against a memory emulator
+/// stops at the first malformed record
+pub fn replay_mem_operations<P: AsRef<Path>, M: MemoryEmulator>(
+    file_path: P,
+    mem_emulator: &mut M,
+) -> Result<ReplayStats, ReplayError> {
+    let mmap = map_trace(file_path).map_err(ReplayError::io)?;
+    let mut stats = ReplayStats::default();
 
-    unsafe {
-        libc::madvise(
-            mmap.as_ptr() as *mut libc::c_void,
-            mmap.len(),
-            libc::MADV_SEQUENTIAL | libc::MADV_WILLNEED,
-        );
+    for op in ReplayReader::from_slice(&mmap) {
+        let op = op?;
+        stats.record(op.kind);
+        let _ = op.apply(mem_emulator);
     }
 
-    let mut data = ReplayIter { data: &mmap };
-
-    loop {
-        let header = match data.take(10) {
-            Some(h) => h,
-            None => break,
-        };
-        let width = header[1] as usize;
-        let addr = u64::from_le_bytes(header[2..10].try_into().unwrap());
-
-        match header[0] {
-            1 => {
-                let value = data.take(width).unwrap();
-
-                match width {
-                    1 => mem_emulator.store_u8(addr, value[0]),
-                    2 => mem_emulator
-                        .store_u16(addr, u16::from_le_bytes(value[..width].try_into().unwrap())),
-                    4 => mem_emulator
-                        .store_u32(addr, u32::from_le_bytes(value[..width].try_into().unwrap())),
-                    8 => mem_emulator
-                        .store_u64(addr, u64::from_le_bytes(value[..width].try_into().unwrap())),
-                    _ => unreachable!(),
-                }
-            }
-            2 => match width {
-                1 => {
-                    let _ = mem_emulator.load_u8(addr);
-                }
-                2 => {
-                    let _ = mem_emulator.load_u16(addr);
-                }
-                4 => {
-                    let _ = mem_emulator.load_u32(addr);
-                }
-                8 => {
-                    let _ = mem_emulator.load_u64(addr);
-                }
-                _ => unreachable!(),
+    Ok(stats)
+}
+
+/// Check a trace body against the checksum in its file header
+/// this reads the whole trace, so replay doesn't do it on the hot path
+/// returns the header, or `None` for legacy traces which carry no checksum
+pub fn verify_trace<P: AsRef<Path>>(file_path: P) -> Result<Option<TraceHeader>, ReplayError> {
+    let mmap = map_trace(file_path).map_err(ReplayError::io)?;
+    let mut reader = ReplayReader::from_slice(&mmap);
+
+    let Some(header) = reader.header()?.cloned() else {
+        return Ok(None);
+    };
+
+    let actual = Checksum::of(&mmap[reader.offset() as usize..]);
+    if actual != header.checksum {
+        return Err(ReplayError {
+            kind: ReplayErrorKind::ChecksumMismatch {
+                expected: header.checksum,
+                actual,
             },
-            _ => panic!("unknown operation"),
-        }
+            offset: reader.offset(),
+            record: 0,
+        });
     }
+
+    Ok(Some(header))
+}
+
+/// Re-encode a trace, keeping the producer name and load values of headed traces
+/// the output always gets a file header, returns it once the output is complete
+pub fn convert_trace<P: AsRef<Path>, Q: AsRef<Path>>(
+    input_path: P,
+    output_path: Q,
+    encoding: TraceEncoding,
+) -> Result<TraceHeader, ReplayError> {
+    rewrite_trace(
+        input_path,
+        output_path,
+        |input| TraceFormat {
+            encoding,
+            load_values: input.is_some_and(TraceHeader::has_load_values),
+        },
+        |op| op,
+    )
+}
+
+/// Copy every record of a trace through `map` into a new headed trace
+/// `format` picks the output layout given the input header
+pub(crate) fn rewrite_trace<P, Q, F, G>(
+    input_path: P,
+    output_path: Q,
+    format: F,
+    mut map: G,
+) -> Result<TraceHeader, ReplayError>
+where
+    P: AsRef<Path>,
+    Q: AsRef<Path>,
+    F: FnOnce(Option<&TraceHeader>) -> TraceFormat,
+    G: FnMut(MemOp) -> MemOp,
+{
+    let mmap = map_trace(input_path).map_err(ReplayError::io)?;
+    let mut reader = ReplayReader::from_slice(&mmap);
+    let header = reader.header()?;
+    let producer = match header {
+        Some(header) => header.producer.clone(),
+        None => "fast-mem convert".to_string(),
+    };
+    let format = format(header);
+
+    let write_error = |reader: &ReplayReader<_>, e: io::Error| ReplayError {
+        kind: ReplayErrorKind::Io(e),
+        offset: reader.offset(),
+        record: reader.records(),
+    };
+
+    let out = File::create(output_path).map_err(ReplayError::io)?;
+    let mut writer =
+        TraceWriter::with_format(BufWriter::new(out), producer, format).map_err(ReplayError::io)?;
+
+    while let Some(op) = reader.next() {
+        writer
+            .write_op(&map(op?))
+            .map_err(|e| write_error(&reader, e))?;
+    }
+
+    let (_, header) = writer.finish().map_err(|e| write_error(&reader, e))?;
+    Ok(header)
 }
 
 #[cfg(test)]
 mod tests {
+    use std::io::Cursor;
+
     use crate::{
+        MemoryEmulator, convert_trace,
         emulators::{
+            journaled::JournaledMemory,
+            merkle::MerkleMemory,
+            mmap_flat::MmapFlatMemory,
+            page_table::PageTableMemory,
             paged::{
-                PagedMemoryAHash, PagedMemoryDefault, PagedMemoryFxHash, PagedMemoryNoHashU64,
+                PagedMemory2M, PagedMemory64K, PagedMemoryAHash, PagedMemoryCowFxHash,
+                PagedMemoryDefault, PagedMemoryFxHash, PagedMemoryNoHashU64,
             },
             paged_last_cache::{
-                PagedMemoryCacheLast, PagedMemoryCacheLastAHash, PagedMemoryCacheLastDefault,
+                PagedMemoryCacheLast2M, PagedMemoryCacheLast64K, PagedMemoryCacheLastAHash,
+                PagedMemoryCacheLastCowFxHash, PagedMemoryCacheLastDefault,
                 PagedMemoryCacheLastFxHash, PagedMemoryCacheLastNoHashU64,
             },
+            paged_split_cache::{
+                PagedMemorySplitCacheAHash, PagedMemorySplitCacheDefault,
+                PagedMemorySplitCacheFxHash, PagedMemorySplitCacheNoHashU64,
+            },
+            paged_tlb::{
+                PagedMemoryTlb8x8, PagedMemoryTlb16x4, PagedMemoryTlb64x4, PagedMemoryTlb256x8,
+            },
+            radix::RadixMemory,
+            region::RegionMemory,
+            timestamped::TimestampedMemory,
         },
+        named_hasher::FxHash,
+        replay_mem_operations,
+        replay_reader::{MemOp, OpKind, ReplayErrorKind, ReplayReader, Width},
         test_memory_emulator,
+        trace_format::{TraceEncoding, TraceWriter},
+        verify_trace,
     };
 
     #[test]
@@ -159,9 +305,118 @@ mod tests {
         test_memory_emulator(PagedMemoryAHash::default());
         test_memory_emulator(PagedMemoryFxHash::default());
         test_memory_emulator(PagedMemoryNoHashU64::default());
+        test_memory_emulator(PagedMemory64K::<FxHash>::default());
+        test_memory_emulator(PagedMemory2M::<FxHash>::default());
+        test_memory_emulator(PagedMemoryCowFxHash::default());
         test_memory_emulator(PagedMemoryCacheLastDefault::default());
         test_memory_emulator(PagedMemoryCacheLastAHash::default());
         test_memory_emulator(PagedMemoryCacheLastFxHash::default());
         test_memory_emulator(PagedMemoryCacheLastNoHashU64::default());
+        test_memory_emulator(PagedMemoryCacheLast64K::<FxHash>::default());
+        test_memory_emulator(PagedMemoryCacheLast2M::<FxHash>::default());
+        test_memory_emulator(PagedMemoryCacheLastCowFxHash::default());
+        test_memory_emulator(PagedMemorySplitCacheDefault::default());
+        test_memory_emulator(PagedMemorySplitCacheAHash::default());
+        test_memory_emulator(PagedMemorySplitCacheFxHash::default());
+        test_memory_emulator(PagedMemorySplitCacheNoHashU64::default());
+        test_memory_emulator(PagedMemoryTlb16x4::default());
+        test_memory_emulator(PagedMemoryTlb64x4::default());
+        test_memory_emulator(PagedMemoryTlb256x8::default());
+        test_memory_emulator(PagedMemoryTlb8x8::default());
+        test_memory_emulator(PageTableMemory::default());
+        test_memory_emulator(RadixMemory::default());
+        test_memory_emulator(RegionMemory::default());
+        test_memory_emulator(MerkleMemory::default());
+
+        let mut journaled = JournaledMemory::new(PagedMemoryFxHash::default());
+        journaled.begin();
+        test_memory_emulator(journaled);
+        test_memory_emulator(MmapFlatMemory::default());
+        test_memory_emulator(TimestampedMemory::new(PagedMemoryFxHash::default(), |_| {}));
+    }
+
+    #[test]
+    #[should_panic(expected = "write out of range: 0xfffffffffffffffe")]
+    fn bulk_store_past_the_top_of_memory() {
+        // radix keeps the default bulk methods
+        RadixMemory::default().store_bytes(u64::MAX - 1, &[0; 4]);
+    }
+
+    #[test]
+    #[should_panic(expected = "read out of range: 0xffffffffffffffff")]
+    fn bulk_load_past_the_top_of_memory() {
+        RadixMemory::default().load_bytes(u64::MAX, &mut [0; 2]);
+    }
+
+    #[test]
+    fn converted_trace_replays_identically() {
+        let dir = std::env::temp_dir();
+        let legacy = dir.join(format!("fast-mem-{}-legacy.bin", std::process::id()));
+        let delta = dir.join(format!("fast-mem-{}-delta.bin", std::process::id()));
+
+        let mut trace = vec![];
+        for i in 0..100u64 {
+            trace.extend_from_slice(&[1, 8]);
+            trace.extend_from_slice(&(0x1000 + i * 8).to_le_bytes());
+            trace.extend_from_slice(&i.to_le_bytes());
+            trace.extend_from_slice(&[2, 4]);
+            trace.extend_from_slice(&(0x1000 + i * 8).to_le_bytes());
+        }
+        std::fs::write(&legacy, &trace).unwrap();
+
+        let header = convert_trace(&legacy, &delta, TraceEncoding::Delta).unwrap();
+        assert_eq!(header.record_count, 200);
+        assert_eq!(header.encoding(), TraceEncoding::Delta);
+
+        let converted = std::fs::read(&delta).unwrap();
+        let original: Vec<_> = ReplayReader::from_slice(&trace)
+            .map(Result::unwrap)
+            .collect();
+        let decoded: Vec<_> = ReplayReader::from_slice(&converted)
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(original, decoded);
+
+        let mut mem = PagedMemoryFxHash::default();
+        let stats = replay_mem_operations(&delta, &mut mem).unwrap();
+        assert_eq!((stats.loads, stats.stores), (100, 100));
+
+        std::fs::remove_file(legacy).unwrap();
+        std::fs::remove_file(delta).unwrap();
+    }
+
+    #[test]
+    fn verify_trace_catches_corrupted_bodies() {
+        let path =
+            std::env::temp_dir().join(format!("fast-mem-{}-checksum.bin", std::process::id()));
+
+        let mut writer = TraceWriter::new(Cursor::new(vec![]), "test").unwrap();
+        for i in 0..10u64 {
+            let store = MemOp {
+                kind: OpKind::Store,
+                width: Width::U64,
+                addr: 0x1000 + i * 8,
+                value: i,
+            };
+            writer.write_op(&store).unwrap();
+        }
+        let mut trace = writer.finish().unwrap().0.into_inner();
+        std::fs::write(&path, &trace).unwrap();
+        let header = verify_trace(&path).unwrap().unwrap();
+        assert_eq!(header.record_count, 10);
+
+        // flip a bit in the value of the last store
+        *trace.last_mut().unwrap() ^= 1;
+        std::fs::write(&path, &trace).unwrap();
+        let err = verify_trace(&path).unwrap_err();
+        assert!(
+            matches!(err.kind, ReplayErrorKind::ChecksumMismatch { expected, actual } if expected != actual)
+        );
+
+        // legacy traces carry no checksum
+        std::fs::write(&path, [2, 8, 0, 0, 0, 0, 0, 0, 0, 0]).unwrap();
+        assert!(verify_trace(&path).unwrap().is_none());
+
+        std::fs::remove_file(path).unwrap();
     }
 }
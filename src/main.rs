@@ -1,53 +1,131 @@
-use fast_mem::MemoryEmulator;
-use fast_mem::emulators::noop::NoopMem;
-use fast_mem::emulators::paged::{
-    PagedMemoryAHash, PagedMemoryDefault, PagedMemoryFxHash, PagedMemoryNoHashU64,
-};
-use fast_mem::emulators::paged_last_cache::{
-    PagedMemoryCacheLast, PagedMemoryCacheLastAHash, PagedMemoryCacheLastDefault,
-    PagedMemoryCacheLastFxHash, PagedMemoryCacheLastNoHashU64,
-};
-use fast_mem::replay_mem_operations;
+use std::{fs::File, io, io::Write, path::Path};
+
+use fast_mem::bench::baseline::{Baseline, Verdict};
+use fast_mem::bench::cli::{Command, OutputFormat, USAGE, parse_args};
+use fast_mem::bench::registry::{Backend, Registry};
+use fast_mem::bench::report::{trace_label, write_csv, write_json, write_text_summary};
+use fast_mem::bench::{Measurement, run_benchmark};
+use fast_mem::verify_trace;
 
 fn main() {
-    bench_fib(PagedMemoryDefault::default());
-    bench_fib(PagedMemoryAHash::default());
-    bench_fib(PagedMemoryFxHash::default());
-    bench_fib(PagedMemoryNoHashU64::default());
-
-    bench_exec_block(PagedMemoryDefault::default());
-    bench_exec_block(PagedMemoryAHash::default());
-    bench_exec_block(PagedMemoryFxHash::default());
-    bench_exec_block(PagedMemoryNoHashU64::default());
-
-    bench_fib(PagedMemoryCacheLastDefault::default());
-    bench_fib(PagedMemoryCacheLastAHash::default());
-    bench_fib(PagedMemoryCacheLastFxHash::default());
-    bench_fib(PagedMemoryCacheLastNoHashU64::default());
-
-    bench_exec_block(PagedMemoryCacheLastDefault::default());
-    bench_exec_block(PagedMemoryCacheLastAHash::default());
-    bench_exec_block(PagedMemoryCacheLastFxHash::default());
-    bench_exec_block(PagedMemoryCacheLastNoHashU64::default());
-
-    // bench_exec_block(PagedMemoryCacheLastFxHash::default());
-}
+    let options = match parse_args(std::env::args().skip(1)) {
+        Ok(Command::Run(options)) => options,
+        Ok(Command::List) => {
+            for backend in Registry::default().iter() {
+                println!("{:<20} {}", backend.key, backend.name);
+            }
+            return;
+        }
+        Ok(Command::Help) => {
+            println!("{}", USAGE);
+            return;
+        }
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let baseline = options.baseline.as_ref().map(|path| {
+        Baseline::load(path).unwrap_or_else(|e| {
+            eprintln!("failed to read baseline {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    });
+
+    if options.verify {
+        for trace in &options.traces {
+            if let Err(e) = verify_trace(trace) {
+                eprintln!("verification of {} failed: {}", trace.display(), e);
+                std::process::exit(1);
+            }
+        }
+    }
+
+    let registry = Registry::default();
+    let backends: Vec<&Backend> = if options.backends.is_empty() {
+        registry.iter().collect()
+    } else {
+        options
+            .backends
+            .iter()
+            .map(|name| {
+                registry.get(name).unwrap_or_else(|| {
+                    eprintln!("error: unknown backend {} (see --list)", name);
+                    std::process::exit(2);
+                })
+            })
+            .collect()
+    };
+
+    let text = options.format == OutputFormat::Text;
+    let mut measurements = vec![];
+    for trace in &options.traces {
+        for backend in &backends {
+            if text {
+                println!("{}: {}", backend.name, trace_label(trace));
+            }
 
-fn bench_exec_block<M: MemoryEmulator>(emulator: M) {
-    let label = format!("{}: exec_block", emulator.name());
-    bench_memory_replay(label, "mem_bin/mem-exec-block-gc.bin", emulator);
+            let measurement = run_benchmark(backend, trace, options.warmup, options.reps, |run| {
+                if text {
+                    println!("{:?}", run.elapsed);
+                }
+            })
+            .unwrap_or_else(|e| {
+                eprintln!("replay of {} failed: {}", trace.display(), e);
+                std::process::exit(1);
+            });
+
+            if text && options.reps > 1 {
+                let _ = write_text_summary(&mut io::stdout(), &measurement);
+            }
+            measurements.push(measurement);
+        }
+    }
+
+    if let Err(e) = write_results(options.format, &mut io::stdout().lock(), &measurements) {
+        eprintln!("failed to write results: {}", e);
+        std::process::exit(1);
+    }
+
+    if let Some(path) = &options.output {
+        save_results(path, OutputFormat::for_file(path), &measurements);
+    }
+    if let Some(path) = &options.save_baseline {
+        save_results(path, OutputFormat::Csv, &measurements);
+    }
+
+    if let Some(baseline) = &baseline {
+        let comparisons = baseline.compare(&measurements, options.threshold);
+        // keep stdout machine readable for json and csv
+        for comparison in &comparisons {
+            eprintln!("{}", comparison);
+        }
+        if comparisons.iter().any(|c| c.verdict == Verdict::Slower) {
+            std::process::exit(3);
+        }
+    }
 }
-fn bench_fib<M: MemoryEmulator>(emulator: M) {
-    let label = format!("{}: fib", emulator.name());
-    bench_memory_replay(label, "mem_bin/mem-fib-gc.bin", emulator);
+
+fn save_results(path: &Path, format: OutputFormat, measurements: &[Measurement]) {
+    let written = File::create(path).and_then(|mut file| {
+        write_results(format, &mut file, measurements)?;
+        file.flush()
+    });
+    if let Err(e) = written {
+        eprintln!("failed to write {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
 }
 
-/// Time a memory emulator against a replay file
-fn bench_memory_replay<M: MemoryEmulator>(label: String, path: &'static str, mut emulator: M) {
-    let start = std::time::Instant::now();
-    println!("{}", label);
-    replay_mem_operations(path, &mut emulator);
-    let duration = start.elapsed();
-    println!("{:?}", duration);
-    emulator.finish();
+fn write_results<W: Write>(
+    format: OutputFormat,
+    out: &mut W,
+    measurements: &[Measurement],
+) -> io::Result<()> {
+    match format {
+        OutputFormat::Text => Ok(()),
+        OutputFormat::Json => write_json(out, measurements),
+        OutputFormat::Csv => write_csv(out, measurements),
+    }
 }
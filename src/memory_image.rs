@@ -0,0 +1,369 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::{MemoryEmulator, replay_reader::map_file, trace_format::Checksum};
+
+/// First bytes of a memory image
+pub const IMAGE_MAGIC: [u8; 8] = *b"\x89FMIMAGE";
+/// Latest image version understood by this crate
+pub const IMAGE_VERSION: u16 = 1;
+/// Size of the image header
+/// [magic: 8][version: 2][page_shift: 2][page_count: 8][checksum: 8]
+const IMAGE_HEADER_LEN: usize = 28;
+/// Size of the page index preceding the bytes of every page
+const PAGE_INDEX_LEN: usize = 8;
+/// Largest page an image may hold, 2 GiB
+const MAX_PAGE_SHIFT: u32 = 31;
+
+/// Metadata stored at the start of a memory image
+/// the body is `page_count` records of [page index: 8 bytes LE][page bytes]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageHeader {
+    pub version: u16,
+    /// Pages are `1 << page_shift` bytes long
+    pub page_shift: u32,
+    /// Number of non-zero pages in the image
+    pub page_count: u64,
+    /// `Checksum` of every byte following the header
+    pub checksum: u64,
+}
+
+/// Reasons an image is rejected
+#[derive(Debug)]
+pub enum ImageError {
+    Io(io::Error),
+    /// The file doesn't start with `IMAGE_MAGIC`, or is too short to hold a header
+    NotAnImage,
+    UnsupportedVersion(u16),
+    BadPageShift(u32),
+    /// The body length doesn't match the declared page count
+    LengthMismatch {
+        expected: u64,
+        actual: u64,
+    },
+    /// The page index doesn't fit the 64-bit address space
+    BadPageIndex(u64),
+    ChecksumMismatch {
+        expected: u64,
+        actual: u64,
+    },
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ImageError::Io(e) => write!(f, "image i/o error: {}", e),
+            ImageError::NotAnImage => write!(f, "not a memory image"),
+            ImageError::UnsupportedVersion(v) => write!(f, "unsupported image version {}", v),
+            ImageError::BadPageShift(shift) => write!(f, "invalid page shift {}", shift),
+            ImageError::LengthMismatch { expected, actual } => write!(
+                f,
+                "image body should be {} bytes, found {}",
+                expected, actual
+            ),
+            ImageError::BadPageIndex(idx) => write!(f, "page index 0x{:x} out of range", idx),
+            ImageError::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: header 0x{:016x}, image 0x{:016x}",
+                expected, actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ImageError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ImageError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl ImageHeader {
+    /// Size of a page in bytes
+    pub fn page_size(&self) -> usize {
+        1 << self.page_shift
+    }
+
+    /// Encode the header
+    pub fn to_bytes(&self) -> [u8; IMAGE_HEADER_LEN] {
+        let mut out = [0; IMAGE_HEADER_LEN];
+        out[..8].copy_from_slice(&IMAGE_MAGIC);
+        out[8..10].copy_from_slice(&self.version.to_le_bytes());
+        out[10..12].copy_from_slice(&(self.page_shift as u16).to_le_bytes());
+        out[12..20].copy_from_slice(&self.page_count.to_le_bytes());
+        out[20..28].copy_from_slice(&self.checksum.to_le_bytes());
+        out
+    }
+
+    /// Decode the header at the start of an image
+    pub fn parse(data: &[u8]) -> Result<Self, ImageError> {
+        if data.len() < IMAGE_HEADER_LEN || !data.starts_with(&IMAGE_MAGIC) {
+            return Err(ImageError::NotAnImage);
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes(data[at..at + 2].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+        let version = u16_at(8);
+        if version == 0 || version > IMAGE_VERSION {
+            return Err(ImageError::UnsupportedVersion(version));
+        }
+
+        let page_shift = u16_at(10) as u32;
+        if page_shift > MAX_PAGE_SHIFT {
+            return Err(ImageError::BadPageShift(page_shift));
+        }
+
+        Ok(Self {
+            version,
+            page_shift,
+            page_count: u64_at(12),
+            checksum: u64_at(20),
+        })
+    }
+}
+
+/// Write pages of `1 << page_shift` bytes as a memory image
+/// pages are sorted by index and all-zero pages are skipped,
+/// so memories with the same contents give byte identical images
+pub fn write_image<'a, W: Write + Seek>(
+    mut out: W,
+    page_shift: u32,
+    pages: impl IntoIterator<Item = (u64, &'a [u8])>,
+) -> io::Result<ImageHeader> {
+    assert!(page_shift <= MAX_PAGE_SHIFT, "page too large");
+
+    let mut pages: Vec<_> = pages
+        .into_iter()
+        .filter(|(_, page)| page.iter().any(|&b| b != 0))
+        .collect();
+    pages.sort_unstable_by_key(|(idx, _)| *idx);
+
+    let start = out.stream_position()?;
+    let mut header = ImageHeader {
+        version: IMAGE_VERSION,
+        page_shift,
+        page_count: pages.len() as u64,
+        checksum: 0,
+    };
+    out.write_all(&header.to_bytes())?;
+
+    let mut checksum = Checksum::default();
+    for (idx, page) in pages {
+        assert_eq!(
+            page.len(),
+            1 << page_shift,
+            "page 0x{:x} has the wrong size",
+            idx
+        );
+        let idx = idx.to_le_bytes();
+        checksum.update(&idx);
+        checksum.update(page);
+        out.write_all(&idx)?;
+        out.write_all(page)?;
+    }
+
+    // fill in the checksum now that the body is written
+    header.checksum = checksum.finish();
+    let end = out.stream_position()?;
+    out.seek(SeekFrom::Start(start))?;
+    out.write_all(&header.to_bytes())?;
+    out.seek(SeekFrom::Start(end))?;
+    Ok(header)
+}
+
+/// Write a memory image to a file, see `write_image`
+pub fn save_image<'a, P: AsRef<Path>>(
+    path: P,
+    page_shift: u32,
+    pages: impl IntoIterator<Item = (u64, &'a [u8])>,
+) -> io::Result<ImageHeader> {
+    let mut out = BufWriter::new(File::create(path)?);
+    let header = write_image(&mut out, page_shift, pages)?;
+    out.flush()?;
+    Ok(header)
+}
+
+/// Check an image and iterate over its pages
+/// returns the header and every page with its index, in ascending index order
+pub fn read_image(
+    data: &[u8],
+) -> Result<(ImageHeader, impl Iterator<Item = (u64, &[u8])>), ImageError> {
+    let header = ImageHeader::parse(data)?;
+    let body = &data[IMAGE_HEADER_LEN..];
+
+    let record_len = PAGE_INDEX_LEN + header.page_size();
+    let expected = header.page_count.saturating_mul(record_len as u64);
+    if body.len() as u64 != expected {
+        return Err(ImageError::LengthMismatch {
+            expected,
+            actual: body.len() as u64,
+        });
+    }
+
+    let actual = Checksum::of(body);
+    if actual != header.checksum {
+        return Err(ImageError::ChecksumMismatch {
+            expected: header.checksum,
+            actual,
+        });
+    }
+
+    let max_idx = u64::MAX >> header.page_shift;
+    let pages = body.chunks_exact(record_len).map(|record| {
+        let idx = u64::from_le_bytes(record[..PAGE_INDEX_LEN].try_into().unwrap());
+        (idx, &record[PAGE_INDEX_LEN..])
+    });
+    if let Some((idx, _)) = pages.clone().find(|&(idx, _)| idx > max_idx) {
+        return Err(ImageError::BadPageIndex(idx));
+    }
+
+    Ok((header, pages))
+}
+
+/// Store every page of an image file into a memory emulator
+/// the emulator's own page size doesn't need to match the image's
+pub fn load_image<P: AsRef<Path>, M: MemoryEmulator>(
+    path: P,
+    mem: &mut M,
+) -> Result<ImageHeader, ImageError> {
+    let file = File::open(path).map_err(ImageError::Io)?;
+    // an empty file can't be mapped, neither holds a header
+    if file.metadata().map_err(ImageError::Io)?.len() < IMAGE_HEADER_LEN as u64 {
+        return Err(ImageError::NotAnImage);
+    }
+    let mmap = map_file(&file).map_err(ImageError::Io)?;
+    let (header, pages) = read_image(&mmap)?;
+
+    for (idx, page) in pages {
+        mem.store_bytes(idx << header.page_shift, page);
+    }
+    Ok(header)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        MemoryEmulator,
+        emulators::{
+            paged::{PagedMemory64K, PagedMemoryFxHash},
+            paged_last_cache::PagedMemoryCacheLastFxHash,
+            radix::RadixMemory,
+        },
+        memory_image::{ImageError, load_image, read_image, write_image},
+        named_hasher::FxHash,
+    };
+
+    fn image_of(pages: impl IntoIterator<Item = (u64, Vec<u8>)>, page_shift: u32) -> Vec<u8> {
+        let pages: Vec<_> = pages.into_iter().collect();
+        let mut out = Cursor::new(vec![]);
+        write_image(
+            &mut out,
+            page_shift,
+            pages.iter().map(|(idx, page)| (*idx, page.as_slice())),
+        )
+        .unwrap();
+        out.into_inner()
+    }
+
+    #[test]
+    fn zero_pages_are_skipped_and_pages_sorted() {
+        let image = image_of([(9, vec![1; 16]), (2, vec![0; 16]), (4, vec![2; 16])], 4);
+        let (header, pages) = read_image(&image).unwrap();
+        assert_eq!(header.page_count, 2);
+
+        let indices: Vec<u64> = pages.map(|(idx, _)| idx).collect();
+        assert_eq!(indices, [4, 9]);
+    }
+
+    #[test]
+    fn corrupt_images_are_rejected() {
+        let mut image = image_of([(1, vec![1; 16])], 4);
+        let last = image.len() - 1;
+        image[last] ^= 1;
+        assert!(matches!(
+            read_image(&image),
+            Err(ImageError::ChecksumMismatch { .. })
+        ));
+
+        image.pop();
+        assert!(matches!(
+            read_image(&image),
+            Err(ImageError::LengthMismatch { .. })
+        ));
+        assert!(matches!(
+            read_image(b"FMTRACE"),
+            Err(ImageError::NotAnImage)
+        ));
+
+        let image = image_of([(1 << 60, vec![1; 16])], 4);
+        assert!(matches!(
+            read_image(&image),
+            Err(ImageError::BadPageIndex(_))
+        ));
+    }
+
+    #[test]
+    fn images_load_into_any_backend() {
+        let mut mem = PagedMemoryFxHash::default();
+        mem.store_u64(0x1000, 0x0123_4567_89AB_CDEF);
+        mem.store_u32(0x1FFE, 0xAABB_CCDD);
+        mem.store_u64(0xFFFF_FFFF_FFFF_FF00, 42);
+        // read back as zero, so it doesn't reach the image
+        mem.store_u8(0x9000, 0);
+
+        let dir = std::env::temp_dir();
+        let path = dir.join(format!("fast-mem-{}-image.bin", std::process::id()));
+        let header = mem.save_image(&path).unwrap();
+        assert_eq!(header.page_count, 3);
+
+        let mut radix = RadixMemory::default();
+        load_image(&path, &mut radix).unwrap();
+        let mut large_pages = PagedMemory64K::<FxHash>::default();
+        load_image(&path, &mut large_pages).unwrap();
+
+        for loaded in [
+            &mut radix as &mut dyn MemoryEmulator,
+            &mut large_pages as &mut dyn MemoryEmulator,
+        ] {
+            assert_eq!(loaded.load_u64(0x1000), 0x0123_4567_89AB_CDEF);
+            assert_eq!(loaded.load_u32(0x1FFE), 0xAABB_CCDD);
+            assert_eq!(loaded.load_u64(0xFFFF_FFFF_FFFF_FF00), 42);
+        }
+
+        // the same contents give the same image, whatever the backend
+        let mut cached = PagedMemoryCacheLastFxHash::default();
+        load_image(&path, &mut cached).unwrap();
+        let other = dir.join(format!("fast-mem-{}-image-cached.bin", std::process::id()));
+        cached.save_image(&other).unwrap();
+        assert_eq!(
+            std::fs::read(&path).unwrap(),
+            std::fs::read(&other).unwrap()
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&other).unwrap();
+    }
+
+    #[test]
+    fn empty_and_short_files_are_not_images() {
+        let path = std::env::temp_dir().join(format!("fast-mem-{}-short.bin", std::process::id()));
+        let mut mem = PagedMemoryFxHash::default();
+        for contents in [&b""[..], &b"\x89FMIMAGE"[..]] {
+            std::fs::write(&path, contents).unwrap();
+            assert!(matches!(
+                load_image(&path, &mut mem),
+                Err(ImageError::NotAnImage)
+            ));
+        }
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,782 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, Read},
+    path::Path,
+};
+
+use memmap2::Mmap;
+
+use crate::{
+    MemoryEmulator,
+    trace_format::{
+        FIXED_HEADER_LEN, HeaderError, MAX_DELTA_RECORD_LEN, OP_LOAD, OP_STORE,
+        RECORD_HEADER_LEN as HEADER_LEN, TRACE_MAGIC, TraceEncoding, TraceHeader, VarintError,
+        decode_tag, read_varint, zigzag_decode,
+    },
+};
+
+/// Default capacity of the buffer used when decoding from a `Read`
+const READ_BUF_CAPACITY: usize = 4 << 20;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OpKind {
+    Load,
+    Store,
+}
+
+/// Width of a memory access in bytes
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Width {
+    U8,
+    U16,
+    U32,
+    U64,
+}
+
+impl Width {
+    /// Decode a width from its byte count
+    #[inline]
+    pub fn from_bytes(n: u8) -> Option<Self> {
+        match n {
+            1 => Some(Width::U8),
+            2 => Some(Width::U16),
+            4 => Some(Width::U32),
+            8 => Some(Width::U64),
+            _ => None,
+        }
+    }
+
+    /// Number of bytes accessed
+    #[inline]
+    pub fn bytes(self) -> usize {
+        match self {
+            Width::U8 => 1,
+            Width::U16 => 2,
+            Width::U32 => 4,
+            Width::U64 => 8,
+        }
+    }
+}
+
+/// A single decoded memory operation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemOp {
+    pub kind: OpKind,
+    pub width: Width,
+    pub addr: u64,
+    /// Stored value for stores, zero extended to 64 bits
+    /// for loads, the expected value in traces with load values and 0 otherwise
+    pub value: u64,
+}
+
+impl MemOp {
+    /// Apply the operation to a memory emulator
+    /// returns the loaded value (zero extended) for loads and `None` for stores
+    #[inline]
+    pub fn apply<M: MemoryEmulator>(&self, mem: &mut M) -> Option<u64> {
+        match self.kind {
+            OpKind::Store => {
+                match self.width {
+                    Width::U8 => mem.store_u8(self.addr, self.value as u8),
+                    Width::U16 => mem.store_u16(self.addr, self.value as u16),
+                    Width::U32 => mem.store_u32(self.addr, self.value as u32),
+                    Width::U64 => mem.store_u64(self.addr, self.value),
+                }
+                None
+            }
+            OpKind::Load => Some(match self.width {
+                Width::U8 => mem.load_u8(self.addr) as u64,
+                Width::U16 => mem.load_u16(self.addr) as u64,
+                Width::U32 => mem.load_u32(self.addr) as u64,
+                Width::U64 => mem.load_u64(self.addr),
+            }),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ReplayErrorKind {
+    /// The trace could not be opened, mapped or read
+    Io(io::Error),
+    /// The trace ended in the middle of a record
+    Truncated { needed: usize, available: usize },
+    /// The trace ended in the middle of a delta encoded address,
+    /// whose length isn't known until its last byte
+    TruncatedVarint { available: usize },
+    /// The record opcode is neither a load nor a store
+    BadOpcode(u8),
+    /// The record width is not 1, 2, 4 or 8 bytes
+    BadWidth(u8),
+    /// A delta encoded address doesn't fit in 64 bits
+    BadAddressDelta,
+    /// The file header is present but invalid
+    Header(HeaderError),
+    /// The trace holds a different number of records than its header declares
+    RecordCountMismatch { expected: u64, actual: u64 },
+    /// The trace body doesn't match the checksum in its header
+    ChecksumMismatch { expected: u64, actual: u64 },
+}
+
+/// Failure to decode a trace, located by byte offset and record index
+#[derive(Debug)]
+pub struct ReplayError {
+    pub kind: ReplayErrorKind,
+    /// Byte offset of the start of the failing record
+    pub offset: u64,
+    /// Zero based index of the failing record
+    pub record: u64,
+}
+
+impl ReplayError {
+    /// An I/O error that happened before any record was decoded
+    pub fn io(err: io::Error) -> Self {
+        Self {
+            kind: ReplayErrorKind::Io(err),
+            offset: 0,
+            record: 0,
+        }
+    }
+}
+
+impl fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            ReplayErrorKind::Io(e) => write!(f, "trace i/o error: {}", e)?,
+            ReplayErrorKind::Truncated { needed, available } => write!(
+                f,
+                "truncated record: needed {} bytes, {} available",
+                needed, available
+            )?,
+            ReplayErrorKind::TruncatedVarint { available } => write!(
+                f,
+                "truncated record: address delta cut off, {} bytes available",
+                available
+            )?,
+            ReplayErrorKind::BadOpcode(op) => write!(f, "unknown operation 0x{:02x}", op)?,
+            ReplayErrorKind::BadWidth(width) => write!(f, "invalid access width {}", width)?,
+            ReplayErrorKind::BadAddressDelta => write!(f, "address delta overflows 64 bits")?,
+            ReplayErrorKind::Header(e) => write!(f, "invalid trace header: {}", e)?,
+            ReplayErrorKind::RecordCountMismatch { expected, actual } => write!(
+                f,
+                "header declares {} records, trace holds {}",
+                expected, actual
+            )?,
+            ReplayErrorKind::ChecksumMismatch { expected, actual } => write!(
+                f,
+                "checksum mismatch: header 0x{:016x}, trace 0x{:016x}",
+                expected, actual
+            )?,
+        }
+        write!(
+            f,
+            " (record {}, byte offset 0x{:x})",
+            self.record, self.offset
+        )
+    }
+}
+
+impl std::error::Error for ReplayError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            ReplayErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// A source of raw trace bytes
+pub trait TraceSource {
+    /// Returns the next `n` bytes without consuming them
+    /// fewer than `n` bytes are returned only at the end of the trace
+    fn peek(&mut self, n: usize) -> io::Result<&[u8]>;
+
+    /// Advance past `n` bytes, `n` must not exceed what the last `peek` returned
+    fn consume(&mut self, n: usize);
+}
+
+/// Trace bytes that are already in memory (e.g. an mmap)
+pub struct SliceSource<'a> {
+    data: &'a [u8],
+}
+
+impl TraceSource for SliceSource<'_> {
+    #[inline]
+    fn peek(&mut self, n: usize) -> io::Result<&[u8]> {
+        Ok(&self.data[..n.min(self.data.len())])
+    }
+
+    #[inline]
+    fn consume(&mut self, n: usize) {
+        self.data = &self.data[n..];
+    }
+}
+
+/// Trace bytes pulled from any `Read` through an internal buffer
+pub struct ReadSource<R: Read> {
+    inner: R,
+    buf: Vec<u8>,
+    start: usize,
+    end: usize,
+}
+
+impl<R: Read> TraceSource for ReadSource<R> {
+    fn peek(&mut self, n: usize) -> io::Result<&[u8]> {
+        if self.end - self.start < n {
+            // move the unread tail to the front and refill behind it
+            self.buf.copy_within(self.start..self.end, 0);
+            self.end -= self.start;
+            self.start = 0;
+            if self.buf.len() < n {
+                self.buf.resize(n, 0);
+            }
+
+            while self.end < n {
+                match self.inner.read(&mut self.buf[self.end..]) {
+                    Ok(0) => break,
+                    Ok(read) => self.end += read,
+                    Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+
+        let available = (self.end - self.start).min(n);
+        Ok(&self.buf[self.start..self.start + available])
+    }
+
+    #[inline]
+    fn consume(&mut self, n: usize) {
+        self.start += n;
+    }
+}
+
+/// Decodes a memory trace into a stream of `MemOp`s
+/// stops after the first error
+pub struct ReplayReader<S: TraceSource> {
+    source: S,
+    /// Byte offset of the next record
+    offset: u64,
+    /// Index of the next record
+    record: u64,
+    /// File header, `None` for legacy headerless traces
+    header: Option<TraceHeader>,
+    header_read: bool,
+    encoding: TraceEncoding,
+    /// Load records carry their expected value
+    load_values: bool,
+    /// Address of the previous record, the base for delta encoded addresses
+    prev_addr: u64,
+    failed: bool,
+}
+
+impl<'a> ReplayReader<SliceSource<'a>> {
+    /// Decode a trace held in memory, e.g. a mapped trace file
+    pub fn from_slice(data: &'a [u8]) -> Self {
+        Self::new(SliceSource { data })
+    }
+}
+
+impl<R: Read> ReplayReader<ReadSource<R>> {
+    /// Decode a trace from any reader
+    pub fn from_read(inner: R) -> Self {
+        Self::new(ReadSource {
+            inner,
+            buf: vec![0; READ_BUF_CAPACITY],
+            start: 0,
+            end: 0,
+        })
+    }
+}
+
+impl<S: TraceSource> ReplayReader<S> {
+    pub fn new(source: S) -> Self {
+        Self {
+            source,
+            offset: 0,
+            record: 0,
+            header: None,
+            header_read: false,
+            encoding: TraceEncoding::Fixed,
+            load_values: false,
+            prev_addr: 0,
+            failed: false,
+        }
+    }
+
+    /// Detect and validate the file header, if any
+    /// returns `None` for legacy headerless traces
+    pub fn header(&mut self) -> Result<Option<&TraceHeader>, ReplayError> {
+        if !self.header_read {
+            self.read_header()?;
+        }
+        Ok(self.header.as_ref())
+    }
+
+    fn read_header(&mut self) -> Result<(), ReplayError> {
+        self.header_read = true;
+
+        let present = match self.source.peek(TRACE_MAGIC.len()) {
+            Ok(magic) => TraceHeader::is_present(magic),
+            Err(e) => return Err(self.error(ReplayErrorKind::Io(e))),
+        };
+        if !present {
+            return Ok(());
+        }
+
+        let fixed = self.peek_exact(FIXED_HEADER_LEN).map_err(header_error)?;
+        let header_len = TraceHeader::declared_len(fixed).max(FIXED_HEADER_LEN);
+        let bytes = self.peek_exact(header_len).map_err(header_error)?;
+        let (header, header_len) =
+            TraceHeader::parse(bytes).map_err(|e| self.error(ReplayErrorKind::Header(e)))?;
+
+        self.source.consume(header_len);
+        self.offset += header_len as u64;
+        self.encoding = header.encoding();
+        self.load_values = header.has_load_values();
+        self.header = Some(header);
+        Ok(())
+    }
+
+    /// Byte offset of the next record
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Number of records decoded so far
+    pub fn records(&self) -> u64 {
+        self.record
+    }
+
+    #[inline]
+    fn error(&self, kind: ReplayErrorKind) -> ReplayError {
+        ReplayError {
+            kind,
+            offset: self.offset,
+            record: self.record,
+        }
+    }
+
+    /// Peek exactly `n` bytes of the current record
+    #[inline]
+    fn peek_exact(&mut self, n: usize) -> Result<&[u8], ReplayError> {
+        let (offset, record) = (self.offset, self.record);
+        let kind = match self.source.peek(n) {
+            Ok(bytes) if bytes.len() == n => return Ok(&bytes[..n]),
+            Ok(bytes) => ReplayErrorKind::Truncated {
+                needed: n,
+                available: bytes.len(),
+            },
+            Err(e) => ReplayErrorKind::Io(e),
+        };
+
+        Err(ReplayError {
+            kind,
+            offset,
+            record,
+        })
+    }
+
+    /// Called at a clean end of trace
+    fn check_record_count(&self) -> Result<(), ReplayError> {
+        match &self.header {
+            Some(header) if header.record_count != self.record => {
+                Err(self.error(ReplayErrorKind::RecordCountMismatch {
+                    expected: header.record_count,
+                    actual: self.record,
+                }))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    #[inline]
+    fn next_op(&mut self) -> Result<Option<MemOp>, ReplayError> {
+        if !self.header_read {
+            self.read_header()?;
+        }
+
+        let decoded = match self.encoding {
+            TraceEncoding::Fixed => self.decode_fixed()?,
+            TraceEncoding::Delta => self.decode_delta()?,
+        };
+        let Some((op, len)) = decoded else {
+            return self.check_record_count().map(|_| None);
+        };
+
+        self.source.consume(len);
+        self.offset += len as u64;
+        self.record += 1;
+        Ok(Some(op))
+    }
+
+    /// Decode a fixed layout record, returning it and its encoded length
+    #[inline]
+    fn decode_fixed(&mut self) -> Result<Option<(MemOp, usize)>, ReplayError> {
+        let header = self.peek_exact(HEADER_LEN);
+        let header = match header {
+            Ok(header) => header,
+            // a clean end of trace is a truncation with nothing left
+            Err(ReplayError {
+                kind: ReplayErrorKind::Truncated { available: 0, .. },
+                ..
+            }) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+
+        let op = header[0];
+        let raw_width = header[1];
+        let addr = u64::from_le_bytes(header[2..10].try_into().unwrap());
+
+        let kind = match op {
+            OP_STORE => OpKind::Store,
+            OP_LOAD => OpKind::Load,
+            _ => return Err(self.error(ReplayErrorKind::BadOpcode(op))),
+        };
+        let width = Width::from_bytes(raw_width)
+            .ok_or_else(|| self.error(ReplayErrorKind::BadWidth(raw_width)))?;
+
+        let (value, len) = if kind == OpKind::Store || self.load_values {
+            let n = width.bytes();
+            let bytes = self.peek_exact(HEADER_LEN + n)?;
+            (read_value(&bytes[HEADER_LEN..]), HEADER_LEN + n)
+        } else {
+            (0, HEADER_LEN)
+        };
+
+        Ok(Some((
+            MemOp {
+                kind,
+                width,
+                addr,
+                value,
+            },
+            len,
+        )))
+    }
+
+    /// Decode a delta encoded record, returning it and its encoded length
+    #[inline]
+    fn decode_delta(&mut self) -> Result<Option<(MemOp, usize)>, ReplayError> {
+        let (offset, record) = (self.offset, self.record);
+        let error = |kind| ReplayError {
+            kind,
+            offset,
+            record,
+        };
+
+        let bytes = match self.source.peek(MAX_DELTA_RECORD_LEN) {
+            Ok([]) => return Ok(None),
+            Ok(bytes) => bytes,
+            Err(e) => return Err(error(ReplayErrorKind::Io(e))),
+        };
+        let truncated = |needed| {
+            error(ReplayErrorKind::Truncated {
+                needed,
+                available: bytes.len(),
+            })
+        };
+
+        let tag = bytes[0];
+        let (kind, width) =
+            decode_tag(tag).ok_or_else(|| error(ReplayErrorKind::BadOpcode(tag)))?;
+        let (delta, varint_len) = read_varint(&bytes[1..]).map_err(|e| match e {
+            VarintError::Truncated => error(ReplayErrorKind::TruncatedVarint {
+                available: bytes.len(),
+            }),
+            VarintError::Overflow => error(ReplayErrorKind::BadAddressDelta),
+        })?;
+
+        let mut len = 1 + varint_len;
+        let value = if kind == OpKind::Store || self.load_values {
+            let n = width.bytes();
+            if bytes.len() < len + n {
+                return Err(truncated(len + n));
+            }
+            let value = read_value(&bytes[len..len + n]);
+            len += n;
+            value
+        } else {
+            0
+        };
+
+        let addr = self.prev_addr.wrapping_add(zigzag_decode(delta) as u64);
+        self.prev_addr = addr;
+
+        Ok(Some((
+            MemOp {
+                kind,
+                width,
+                addr,
+                value,
+            },
+            len,
+        )))
+    }
+}
+
+/// Zero extend a little endian value of up to 8 bytes
+#[inline]
+fn read_value(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value[..bytes.len()].copy_from_slice(bytes);
+    u64::from_le_bytes(value)
+}
+
+/// Report a short file header as a header error rather than a truncated record
+fn header_error(err: ReplayError) -> ReplayError {
+    match err.kind {
+        ReplayErrorKind::Truncated { needed, available } => ReplayError {
+            kind: ReplayErrorKind::Header(HeaderError::Truncated { needed, available }),
+            ..err
+        },
+        _ => err,
+    }
+}
+
+impl<S: TraceSource> Iterator for ReplayReader<S> {
+    type Item = Result<MemOp, ReplayError>;
+
+    #[inline]
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+
+        let result = self.next_op();
+        self.failed = result.is_err();
+        result.transpose()
+    }
+}
+
+/// Map a whole file into memory, read only
+pub(crate) fn map_file(file: &File) -> io::Result<Mmap> {
+    unsafe { Mmap::map(file) }
+}
+
+/// Map a trace file into memory, advising the kernel that it will be read sequentially
+pub fn map_trace<P: AsRef<Path>>(path: P) -> io::Result<Mmap> {
+    let file = File::open(path)?;
+    let mmap = map_file(&file)?;
+
+    unsafe {
+        libc::madvise(
+            mmap.as_ptr() as *mut libc::c_void,
+            mmap.len(),
+            libc::MADV_SEQUENTIAL | libc::MADV_WILLNEED,
+        );
+    }
+
+    Ok(mmap)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        replay_reader::{MemOp, OpKind, ReplayErrorKind, ReplayReader, Width},
+        trace_format::{Checksum, TraceEncoding, TraceWriter},
+    };
+
+    fn sample_trace() -> Vec<u8> {
+        let mut trace = vec![];
+        // store u16 0xBEEF at 0x1000
+        trace.extend_from_slice(&[1, 2]);
+        trace.extend_from_slice(&0x1000u64.to_le_bytes());
+        trace.extend_from_slice(&0xBEEFu16.to_le_bytes());
+        // load u64 from 0xFFFF_FFFF_FFFF_FF00
+        trace.extend_from_slice(&[2, 8]);
+        trace.extend_from_slice(&0xFFFF_FFFF_FFFF_FF00u64.to_le_bytes());
+        trace
+    }
+
+    fn expected_ops() -> Vec<MemOp> {
+        vec![
+            MemOp {
+                kind: OpKind::Store,
+                width: Width::U16,
+                addr: 0x1000,
+                value: 0xBEEF,
+            },
+            MemOp {
+                kind: OpKind::Load,
+                width: Width::U64,
+                addr: 0xFFFF_FFFF_FFFF_FF00,
+                value: 0,
+            },
+        ]
+    }
+
+    #[test]
+    fn decodes_slice_and_read_sources_identically() {
+        let trace = sample_trace();
+
+        let from_slice: Vec<_> = ReplayReader::from_slice(&trace)
+            .collect::<Result<_, _>>()
+            .unwrap();
+        let from_read: Vec<_> = ReplayReader::from_read(trace.as_slice())
+            .collect::<Result<_, _>>()
+            .unwrap();
+
+        assert_eq!(from_slice, expected_ops());
+        assert_eq!(from_read, expected_ops());
+    }
+
+    fn headed_trace() -> Vec<u8> {
+        let mut writer = TraceWriter::new(Cursor::new(vec![]), "test").unwrap();
+        for op in expected_ops() {
+            writer.write_op(&op).unwrap();
+        }
+        writer.finish().unwrap().0.into_inner()
+    }
+
+    #[test]
+    fn decodes_headed_trace() {
+        let trace = headed_trace();
+
+        let mut reader = ReplayReader::from_read(trace.as_slice());
+        let header = reader.header().unwrap().unwrap().clone();
+        assert_eq!(header.producer, "test");
+        assert_eq!(header.record_count, 2);
+
+        let body_start = reader.offset() as usize;
+        assert_eq!(&trace[body_start..], sample_trace().as_slice());
+        assert_eq!(header.checksum, Checksum::of(&trace[body_start..]));
+
+        let ops: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(ops, expected_ops());
+    }
+
+    #[test]
+    fn delta_encoding_roundtrip() {
+        let mut ops = expected_ops();
+        ops.extend((0..64).map(|i| MemOp {
+            kind: if i % 3 == 0 {
+                OpKind::Store
+            } else {
+                OpKind::Load
+            },
+            width: Width::U32,
+            addr: 0x7fff_0000 - i * 4,
+            value: if i % 3 == 0 { i * 7 } else { 0 },
+        }));
+
+        let mut fixed = TraceWriter::new(Cursor::new(vec![]), "test").unwrap();
+        let mut delta =
+            TraceWriter::with_encoding(Cursor::new(vec![]), "test", TraceEncoding::Delta).unwrap();
+        for op in &ops {
+            fixed.write_op(op).unwrap();
+            delta.write_op(op).unwrap();
+        }
+        let fixed = fixed.finish().unwrap().0.into_inner();
+        let delta = delta.finish().unwrap().0.into_inner();
+        assert!(delta.len() * 2 < fixed.len());
+
+        let mut reader = ReplayReader::from_read(delta.as_slice());
+        assert_eq!(
+            reader.header().unwrap().unwrap().encoding(),
+            TraceEncoding::Delta
+        );
+        let decoded: Vec<_> = reader.collect::<Result<_, _>>().unwrap();
+        assert_eq!(decoded, ops);
+
+        let mut truncated = delta.clone();
+        truncated.truncate(delta.len() - 1);
+        let err = ReplayReader::from_slice(&truncated)
+            .find_map(Result::err)
+            .unwrap();
+        assert!(matches!(err.kind, ReplayErrorKind::Truncated { .. }));
+        assert_eq!(err.record, ops.len() as u64 - 1);
+    }
+
+    #[test]
+    fn reports_varint_cut_off_in_the_middle() {
+        let mut writer =
+            TraceWriter::with_encoding(Cursor::new(vec![]), "test", TraceEncoding::Delta).unwrap();
+        for addr in [0x1000, 0x7fff_0000_0000] {
+            let load = MemOp {
+                kind: OpKind::Load,
+                width: Width::U64,
+                addr,
+                value: 0,
+            };
+            writer.write_op(&load).unwrap();
+        }
+        let mut trace = writer.finish().unwrap().0.into_inner();
+        // the second load is a tag and a 7 byte varint, keep the tag and 4 of them
+        trace.truncate(trace.len() - 3);
+
+        let err = ReplayReader::from_slice(&trace)
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(err.record, 1);
+        assert!(matches!(
+            err.kind,
+            ReplayErrorKind::TruncatedVarint { available: 5 }
+        ));
+    }
+
+    #[test]
+    fn legacy_trace_has_no_header() {
+        let trace = sample_trace();
+        let mut reader = ReplayReader::from_slice(&trace);
+        assert!(reader.header().unwrap().is_none());
+        assert_eq!(reader.count(), 2);
+    }
+
+    #[test]
+    fn detects_record_count_mismatch() {
+        let mut trace = headed_trace();
+        // drop the trailing load
+        trace.truncate(trace.len() - 10);
+
+        let err = ReplayReader::from_slice(&trace)
+            .find_map(Result::err)
+            .unwrap();
+        assert!(matches!(
+            err.kind,
+            ReplayErrorKind::RecordCountMismatch {
+                expected: 2,
+                actual: 1
+            }
+        ));
+    }
+
+    #[test]
+    fn reports_truncated_record_location() {
+        let mut trace = sample_trace();
+        trace.truncate(trace.len() - 3);
+
+        let err = ReplayReader::from_slice(&trace)
+            .find_map(Result::err)
+            .unwrap();
+        assert_eq!(err.record, 1);
+        assert_eq!(err.offset, 12);
+        assert!(matches!(
+            err.kind,
+            ReplayErrorKind::Truncated {
+                needed: 10,
+                available: 7
+            }
+        ));
+    }
+
+    #[test]
+    fn reports_bad_opcode_and_width() {
+        let mut trace = sample_trace();
+        trace[12] = 7;
+        let err = ReplayReader::from_slice(&trace)
+            .find_map(Result::err)
+            .unwrap();
+        assert!(matches!(err.kind, ReplayErrorKind::BadOpcode(7)));
+        assert_eq!((err.record, err.offset), (1, 12));
+
+        let mut trace = sample_trace();
+        trace[1] = 3;
+        let mut reader = ReplayReader::from_slice(&trace);
+        let err = reader.next().unwrap().unwrap_err();
+        assert!(matches!(err.kind, ReplayErrorKind::BadWidth(3)));
+        assert!(reader.next().is_none());
+    }
+}
@@ -0,0 +1,278 @@
+use std::{ops::Deref, path::Path, sync::Arc};
+
+use fxhash::FxHashMap;
+
+use crate::{
+    MemoryEmulator, ReplayStats,
+    replay_reader::{MemOp, ReplayError, ReplayReader, map_trace},
+};
+
+/// Paged memory able to hand out its pages without copying them
+/// implemented by the copy-on-write paged memories, e.g. `PagedMemoryCow`
+pub trait SegmentPages: MemoryEmulator {
+    /// Number of bits to describe entries in a page
+    const PAGE_SHIFT: u32;
+
+    /// The current contents of a page, shared with the caller
+    /// `None` if the page was never allocated, the memory copies it on its next write
+    fn share_page(&mut self, idx: u64) -> Option<PageContents>;
+}
+
+/// The contents of a page at one point in time, read through `Deref` as a `[u8]`
+/// shared with the memory it came from, `to_vec` makes an owned copy
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PageContents(Arc<[u8]>);
+
+impl PageContents {
+    pub(crate) fn new(page: Arc<[u8]>) -> Self {
+        Self(page)
+    }
+}
+
+impl Deref for PageContents {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl AsRef<[u8]> for PageContents {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+/// A page touched by a segment
+#[derive(Debug, Clone)]
+pub struct SegmentPage {
+    pub idx: u64,
+    /// Contents before the segment's first operation, `None` for a zero filled page
+    pub start: Option<PageContents>,
+    /// Contents after the segment's last operation, `None` for a zero filled page
+    pub end: Option<PageContents>,
+}
+
+/// A run of consecutive operations of a trace and the pages they touched
+#[derive(Debug, Clone)]
+pub struct Segment {
+    /// Zero based index of the segment
+    pub index: u64,
+    /// Zero based index of the segment's first record
+    pub first_record: u64,
+    /// Number of records in the segment
+    pub records: u64,
+    /// Every page loaded from or stored to, sorted by index
+    pub pages: Vec<SegmentPage>,
+}
+
+/// A page of `mem` shared with the caller, `None` if it was never allocated or holds only zeroes
+/// some backends allocate pages on loads, this keeps them from reporting a zero filled page
+fn share_nonzero<M: SegmentPages>(mem: &mut M, idx: u64) -> Option<PageContents> {
+    mem.share_page(idx)
+        .filter(|page| page.iter().any(|&b| b != 0))
+}
+
+/// Pages touched by the segment being replayed, with their contents at its start
+struct SegmentBuilder {
+    first_record: u64,
+    records: u64,
+    touched: FxHashMap<u64, Option<PageContents>>,
+    /// Last page touched, to skip the map on runs of accesses to one page
+    last_page: Option<u64>,
+}
+
+impl SegmentBuilder {
+    fn new(first_record: u64) -> Self {
+        Self {
+            first_record,
+            records: 0,
+            touched: FxHashMap::default(),
+            last_page: None,
+        }
+    }
+
+    /// Note the pages `op` touches, before it is applied
+    #[inline]
+    fn touch<M: SegmentPages>(&mut self, mem: &mut M, op: &MemOp) {
+        let first = op.addr >> M::PAGE_SHIFT;
+        let last = op.addr.saturating_add(op.width.bytes() as u64 - 1) >> M::PAGE_SHIFT;
+        if first == last && self.last_page == Some(first) {
+            return;
+        }
+
+        for idx in first..=last {
+            self.touched
+                .entry(idx)
+                .or_insert_with(|| share_nonzero(mem, idx));
+        }
+        self.last_page = Some(last);
+    }
+
+    /// Capture the end contents of every touched page
+    fn finish<M: SegmentPages>(self, mem: &mut M, index: u64) -> Segment {
+        let mut pages: Vec<SegmentPage> = self
+            .touched
+            .into_iter()
+            .map(|(idx, start)| SegmentPage {
+                idx,
+                start,
+                end: share_nonzero(mem, idx),
+            })
+            .collect();
+        pages.sort_unstable_by_key(|page| page.idx);
+
+        Segment {
+            index,
+            first_record: self.first_record,
+            records: self.records,
+            pages,
+        }
+    }
+}
+
+/// Replay a trace cut into segments of `records_per_segment` operations, the last one may be shorter
+/// `on_segment` sees every segment as it completes, with the start and end contents of its pages,
+/// pages are shared with the memory, so only those written during a segment get copied
+pub fn replay_segments<P, M, F>(
+    file_path: P,
+    mem: &mut M,
+    records_per_segment: u64,
+    mut on_segment: F,
+) -> Result<ReplayStats, ReplayError>
+where
+    P: AsRef<Path>,
+    M: SegmentPages,
+    F: FnMut(Segment),
+{
+    assert!(records_per_segment > 0, "segments need at least one record");
+
+    let mmap = map_trace(file_path).map_err(ReplayError::io)?;
+    let mut stats = ReplayStats::default();
+    let mut index = 0;
+    let mut segment = SegmentBuilder::new(0);
+
+    for op in ReplayReader::from_slice(&mmap) {
+        let op = op?;
+        stats.record(op.kind);
+        segment.touch(mem, &op);
+        let _ = op.apply(mem);
+
+        segment.records += 1;
+        if segment.records == records_per_segment {
+            let next = SegmentBuilder::new(stats.records);
+            on_segment(std::mem::replace(&mut segment, next).finish(mem, index));
+            index += 1;
+        }
+    }
+
+    if segment.records > 0 {
+        on_segment(segment.finish(mem, index));
+    }
+
+    Ok(stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use crate::{
+        MemoryEmulator,
+        emulators::{paged::PagedMemoryCowFxHash, paged_last_cache::PagedMemoryCacheLastCowFxHash},
+        replay_reader::{MemOp, OpKind, Width},
+        segments::{Segment, SegmentPages, replay_segments},
+        trace_format::TraceWriter,
+    };
+
+    fn op(kind: OpKind, width: Width, addr: u64, value: u64) -> MemOp {
+        MemOp {
+            kind,
+            width,
+            addr,
+            value,
+        }
+    }
+
+    fn replay<M: SegmentPages + Default>(path: &std::path::Path) -> Vec<Segment> {
+        let mut mem = M::default();
+        let mut segments = vec![];
+        let stats = replay_segments(path, &mut mem, 3, |s| segments.push(s)).unwrap();
+        assert_eq!(stats.records, 7);
+        segments
+    }
+
+    #[test]
+    fn segments_report_start_and_end_contents() {
+        let path =
+            std::env::temp_dir().join(format!("fast-mem-{}-segments.bin", std::process::id()));
+        let ops = [
+            op(OpKind::Store, Width::U64, 0x1000, 1),
+            op(OpKind::Load, Width::U8, 0x5000, 0),
+            // straddles pages 1 and 2
+            op(OpKind::Store, Width::U32, 0x1FFE, 0xAABB_CCDD),
+            op(OpKind::Load, Width::U64, 0x1000, 1),
+            op(OpKind::Store, Width::U64, 0x1000, 2),
+            op(OpKind::Store, Width::U16, 0x9000, 3),
+            op(OpKind::Load, Width::U16, 0x9000, 3),
+        ];
+        let mut writer = TraceWriter::new(Cursor::new(vec![]), "test").unwrap();
+        for op in &ops {
+            writer.write_op(op).unwrap();
+        }
+        fs::write(&path, writer.finish().unwrap().0.into_inner()).unwrap();
+
+        let paged = replay::<PagedMemoryCowFxHash>(&path);
+        let cached = replay::<PagedMemoryCacheLastCowFxHash>(&path);
+        fs::remove_file(&path).unwrap();
+
+        for segments in [paged, cached] {
+            let shape: Vec<_> = segments
+                .iter()
+                .map(|s| (s.index, s.first_record, s.records))
+                .collect();
+            assert_eq!(shape, [(0, 0, 3), (1, 3, 3), (2, 6, 1)]);
+
+            let idxs: Vec<_> = segments[0].pages.iter().map(|p| p.idx).collect();
+            assert_eq!(idxs, [1, 2, 5]);
+            let page1 = &segments[0].pages[0];
+            assert!(page1.start.is_none());
+            let end = page1.end.as_ref().unwrap();
+            assert_eq!(end[0], 1);
+            assert_eq!(end[0xFFE..], [0xDD, 0xCC]);
+            assert_eq!(
+                segments[0].pages[1].end.as_ref().unwrap()[..2],
+                [0xBB, 0xAA]
+            );
+            // only loaded from, the cache-last backend allocates it nonetheless
+            let page5 = &segments[0].pages[2];
+            assert!(page5.start.is_none());
+            assert!(page5.end.is_none());
+
+            // the second segment starts where the first ended
+            let idxs: Vec<_> = segments[1].pages.iter().map(|p| p.idx).collect();
+            assert_eq!(idxs, [1, 9]);
+            let page1 = &segments[1].pages[0];
+            assert_eq!(page1.start.as_ref().unwrap()[..], end[..]);
+            assert_eq!(page1.end.as_ref().unwrap()[0], 2);
+
+            let idxs: Vec<_> = segments[2].pages.iter().map(|p| p.idx).collect();
+            assert_eq!(idxs, [9]);
+            let page9 = &segments[2].pages[0];
+            assert_eq!(page9.start, page9.end);
+        }
+    }
+
+    #[test]
+    fn exported_pages_do_not_change_with_later_writes() {
+        let mut mem = PagedMemoryCacheLastCowFxHash::default();
+        mem.store_u64(0x1000, 1);
+        let shared = mem.share_page(1).unwrap();
+
+        // the cached pointer must not write through to the shared page
+        mem.store_u64(0x1000, 2);
+        assert_eq!(shared[0], 1);
+        assert_eq!(mem.load_u64(0x1000), 2);
+        assert!(mem.share_page(7).is_none());
+    }
+}
@@ -0,0 +1,95 @@
+use std::ops::Range;
+
+use crate::{
+    MemoryEmulator,
+    emulators::paged::PagedMemoryFxHash,
+    replay_reader::{MemOp, OpKind, Width},
+};
+
+/// Paged memory misbehaving on one kind of access to a range of addresses
+/// faulty loads come back with their lowest bit flipped, faulty stores are dropped
+pub(crate) struct FaultyMemory {
+    inner: PagedMemoryFxHash,
+    kind: OpKind,
+    addrs: Range<u64>,
+}
+
+impl FaultyMemory {
+    /// Corrupt every load starting in `addrs`
+    pub(crate) fn corrupt_loads(addrs: Range<u64>) -> Self {
+        Self {
+            inner: PagedMemoryFxHash::default(),
+            kind: OpKind::Load,
+            addrs,
+        }
+    }
+
+    /// Drop every store starting in `addrs`
+    pub(crate) fn drop_stores(addrs: Range<u64>) -> Self {
+        Self {
+            inner: PagedMemoryFxHash::default(),
+            kind: OpKind::Store,
+            addrs,
+        }
+    }
+
+    fn access(&mut self, kind: OpKind, width: Width, addr: u64, value: u64) -> u64 {
+        let faulty = kind == self.kind && self.addrs.contains(&addr);
+        let op = MemOp {
+            kind,
+            width,
+            addr,
+            value,
+        };
+
+        match kind {
+            OpKind::Load => op.apply(&mut self.inner).unwrap() ^ faulty as u64,
+            OpKind::Store => {
+                if !faulty {
+                    op.apply(&mut self.inner);
+                }
+                0
+            }
+        }
+    }
+}
+
+impl MemoryEmulator for FaultyMemory {
+    fn load_u8(&mut self, addr: u64) -> u8 {
+        self.access(OpKind::Load, Width::U8, addr, 0) as u8
+    }
+
+    fn load_u16(&mut self, addr: u64) -> u16 {
+        self.access(OpKind::Load, Width::U16, addr, 0) as u16
+    }
+
+    fn load_u32(&mut self, addr: u64) -> u32 {
+        self.access(OpKind::Load, Width::U32, addr, 0) as u32
+    }
+
+    fn load_u64(&mut self, addr: u64) -> u64 {
+        self.access(OpKind::Load, Width::U64, addr, 0)
+    }
+
+    fn store_u8(&mut self, addr: u64, value: u8) {
+        self.access(OpKind::Store, Width::U8, addr, value as u64);
+    }
+
+    fn store_u16(&mut self, addr: u64, value: u16) {
+        self.access(OpKind::Store, Width::U16, addr, value as u64);
+    }
+
+    fn store_u32(&mut self, addr: u64, value: u32) {
+        self.access(OpKind::Store, Width::U32, addr, value as u64);
+    }
+
+    fn store_u64(&mut self, addr: u64, value: u64) {
+        self.access(OpKind::Store, Width::U64, addr, value);
+    }
+
+    fn name(&self) -> String {
+        "FaultyMem".to_string()
+    }
+
+    fn finish(&self) {}
+}
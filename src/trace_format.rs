@@ -0,0 +1,559 @@
+use std::{
+    fmt,
+    io::{self, Seek, SeekFrom, Write},
+};
+
+use crate::replay_reader::{MemOp, OpKind, Width};
+
+/// Opcode of a store record in the trace
+pub(crate) const OP_STORE: u8 = 1;
+/// Opcode of a load record in the trace
+pub(crate) const OP_LOAD: u8 = 2;
+/// Size of a record header: [op][width][addr: 8 bytes LE]
+pub(crate) const RECORD_HEADER_LEN: usize = 10;
+/// Longest LEB128 encoding of a u64
+pub(crate) const MAX_VARINT_LEN: usize = 10;
+/// Longest delta encoded record: [tag][addr delta: varint][value for stores]
+pub(crate) const MAX_DELTA_RECORD_LEN: usize = 1 + MAX_VARINT_LEN + 8;
+/// Tag bit set for stores in the delta encoding, bits 1..3 hold log2 of the width
+const TAG_STORE: u8 = 1;
+
+/// First bytes of a trace with a file header
+/// the leading byte is never a valid opcode, so legacy headerless traces can't be mistaken for it
+pub const TRACE_MAGIC: [u8; 8] = *b"\x89FMTRACE";
+/// Latest file header version understood by this crate
+pub const TRACE_VERSION: u16 = 1;
+/// Size of the fixed part of the file header, the producer name follows it
+/// [magic: 8][version: 2][header_len: 2][flags: 4][record_count: 8][checksum: 8][producer_len: 2]
+pub(crate) const FIXED_HEADER_LEN: usize = 34;
+/// Longest producer name that fits in the header
+const MAX_PRODUCER_LEN: usize = u16::MAX as usize - FIXED_HEADER_LEN;
+
+/// Records use the delta encoding
+pub const FLAG_DELTA: u32 = 1 << 0;
+/// Load records carry the loaded value after the address, like stores do
+pub const FLAG_LOAD_VALUES: u32 = 1 << 1;
+/// Every flag this crate knows how to decode
+const KNOWN_FLAGS: u32 = FLAG_DELTA | FLAG_LOAD_VALUES;
+
+/// On-disk layout of the trace records
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum TraceEncoding {
+    /// [op][width][addr: 8 bytes LE][value for stores], the only layout of headerless traces
+    #[default]
+    Fixed,
+    /// [tag: op and width][zig-zag varint delta from the previous address][value for stores]
+    Delta,
+}
+
+/// Everything that determines how records are laid out
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TraceFormat {
+    pub encoding: TraceEncoding,
+    /// Loads record the value they are expected to return
+    pub load_values: bool,
+}
+
+impl TraceFormat {
+    /// Header flags describing this format
+    pub fn flags(&self) -> u32 {
+        let mut flags = 0;
+        if self.encoding == TraceEncoding::Delta {
+            flags |= FLAG_DELTA;
+        }
+        if self.load_values {
+            flags |= FLAG_LOAD_VALUES;
+        }
+        flags
+    }
+}
+
+/// Metadata stored at the start of a trace file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceHeader {
+    pub version: u16,
+    /// Format flags, see `FLAG_DELTA` and `FLAG_LOAD_VALUES`
+    pub flags: u32,
+    /// Name of the tool that produced the trace
+    pub producer: String,
+    /// Number of records following the header
+    pub record_count: u64,
+    /// `Checksum` of every byte following the header
+    pub checksum: u64,
+}
+
+/// Reasons a file header is rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HeaderError {
+    /// The header is shorter than its declared length
+    Truncated {
+        needed: usize,
+        available: usize,
+    },
+    /// The magic matched but the rest of the header is inconsistent
+    Malformed(&'static str),
+    UnsupportedVersion(u16),
+    UnknownFlags(u32),
+}
+
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HeaderError::Truncated { needed, available } => {
+                write!(f, "header needs {} bytes, {} available", needed, available)
+            }
+            HeaderError::Malformed(reason) => write!(f, "malformed header: {}", reason),
+            HeaderError::UnsupportedVersion(v) => write!(f, "unsupported trace version {}", v),
+            HeaderError::UnknownFlags(flags) => write!(f, "unknown header flags 0x{:x}", flags),
+        }
+    }
+}
+
+impl std::error::Error for HeaderError {}
+
+impl TraceHeader {
+    pub fn new(producer: impl Into<String>) -> Self {
+        Self {
+            version: TRACE_VERSION,
+            flags: 0,
+            producer: producer.into(),
+            record_count: 0,
+            checksum: 0,
+        }
+    }
+
+    /// Whether the trace starts with a file header
+    /// `data` only needs to hold the first `TRACE_MAGIC.len()` bytes
+    pub fn is_present(data: &[u8]) -> bool {
+        data.starts_with(&TRACE_MAGIC)
+    }
+
+    /// Total size of the encoded header in bytes
+    pub fn encoded_len(&self) -> usize {
+        FIXED_HEADER_LEN + self.producer.len()
+    }
+
+    /// Declared length of a header from its fixed part
+    pub(crate) fn declared_len(fixed: &[u8]) -> usize {
+        u16::from_le_bytes(fixed[10..12].try_into().unwrap()) as usize
+    }
+
+    /// Decode a header from the start of a trace
+    /// `data` must begin with `TRACE_MAGIC`, returns the header and its length in bytes
+    pub fn parse(data: &[u8]) -> Result<(Self, usize), HeaderError> {
+        debug_assert!(Self::is_present(data));
+
+        if data.len() < FIXED_HEADER_LEN {
+            return Err(HeaderError::Truncated {
+                needed: FIXED_HEADER_LEN,
+                available: data.len(),
+            });
+        }
+
+        let u16_at = |at: usize| u16::from_le_bytes(data[at..at + 2].try_into().unwrap());
+        let u32_at = |at: usize| u32::from_le_bytes(data[at..at + 4].try_into().unwrap());
+        let u64_at = |at: usize| u64::from_le_bytes(data[at..at + 8].try_into().unwrap());
+
+        let version = u16_at(8);
+        if version == 0 || version > TRACE_VERSION {
+            return Err(HeaderError::UnsupportedVersion(version));
+        }
+
+        let header_len = u16_at(10) as usize;
+        let producer_len = u16_at(32) as usize;
+        if header_len < FIXED_HEADER_LEN + producer_len {
+            return Err(HeaderError::Malformed(
+                "header length too short for producer",
+            ));
+        }
+        if data.len() < header_len {
+            return Err(HeaderError::Truncated {
+                needed: header_len,
+                available: data.len(),
+            });
+        }
+
+        let flags = u32_at(12);
+        if flags & !KNOWN_FLAGS != 0 {
+            return Err(HeaderError::UnknownFlags(flags));
+        }
+
+        let producer =
+            std::str::from_utf8(&data[FIXED_HEADER_LEN..FIXED_HEADER_LEN + producer_len])
+                .map_err(|_| HeaderError::Malformed("producer is not utf-8"))?
+                .to_string();
+
+        Ok((
+            Self {
+                version,
+                flags,
+                producer,
+                record_count: u64_at(16),
+                checksum: u64_at(24),
+            },
+            header_len,
+        ))
+    }
+
+    /// Layout of the records following the header
+    pub fn encoding(&self) -> TraceEncoding {
+        if self.flags & FLAG_DELTA != 0 {
+            TraceEncoding::Delta
+        } else {
+            TraceEncoding::Fixed
+        }
+    }
+
+    /// Whether load records carry their expected value
+    pub fn has_load_values(&self) -> bool {
+        self.flags & FLAG_LOAD_VALUES != 0
+    }
+
+    /// Record layout described by the header flags
+    pub fn format(&self) -> TraceFormat {
+        TraceFormat {
+            encoding: self.encoding(),
+            load_values: self.has_load_values(),
+        }
+    }
+
+    /// Encode the header
+    pub fn to_bytes(&self) -> Vec<u8> {
+        assert!(
+            self.producer.len() <= MAX_PRODUCER_LEN,
+            "producer name too long"
+        );
+
+        let mut out = Vec::with_capacity(self.encoded_len());
+        out.extend_from_slice(&TRACE_MAGIC);
+        out.extend_from_slice(&self.version.to_le_bytes());
+        out.extend_from_slice(&(self.encoded_len() as u16).to_le_bytes());
+        out.extend_from_slice(&self.flags.to_le_bytes());
+        out.extend_from_slice(&self.record_count.to_le_bytes());
+        out.extend_from_slice(&self.checksum.to_le_bytes());
+        out.extend_from_slice(&(self.producer.len() as u16).to_le_bytes());
+        out.extend_from_slice(self.producer.as_bytes());
+        out
+    }
+}
+
+/// Streaming 64-bit checksum over the trace body
+/// consumes 8-byte little endian words, so the result doesn't depend on how the input is split
+#[derive(Debug, Clone)]
+pub struct Checksum {
+    state: u64,
+    len: u64,
+    tail: [u8; 8],
+    tail_len: usize,
+}
+
+impl Default for Checksum {
+    fn default() -> Self {
+        Self {
+            state: 0xcbf2_9ce4_8422_2325,
+            len: 0,
+            tail: [0; 8],
+            tail_len: 0,
+        }
+    }
+}
+
+impl Checksum {
+    const MUL: u64 = 0x517c_c1b7_2722_0a95;
+
+    #[inline]
+    fn mix(&mut self, word: u64) {
+        self.state = (self.state.rotate_left(5) ^ word).wrapping_mul(Self::MUL);
+    }
+
+    pub fn update(&mut self, mut bytes: &[u8]) {
+        self.len += bytes.len() as u64;
+
+        if self.tail_len > 0 {
+            let take = bytes.len().min(8 - self.tail_len);
+            self.tail[self.tail_len..self.tail_len + take].copy_from_slice(&bytes[..take]);
+            self.tail_len += take;
+            bytes = &bytes[take..];
+            if self.tail_len < 8 {
+                return;
+            }
+            self.mix(u64::from_le_bytes(self.tail));
+            self.tail_len = 0;
+        }
+
+        let mut words = bytes.chunks_exact(8);
+        for word in &mut words {
+            self.mix(u64::from_le_bytes(word.try_into().unwrap()));
+        }
+
+        let rest = words.remainder();
+        self.tail[..rest.len()].copy_from_slice(rest);
+        self.tail_len = rest.len();
+    }
+
+    pub fn finish(&self) -> u64 {
+        let mut this = self.clone();
+        if this.tail_len > 0 {
+            this.tail[this.tail_len..].fill(0);
+            this.mix(u64::from_le_bytes(this.tail));
+        }
+        this.mix(this.len);
+        this.state
+    }
+
+    /// Checksum of a complete byte slice
+    pub fn of(bytes: &[u8]) -> u64 {
+        let mut checksum = Self::default();
+        checksum.update(bytes);
+        checksum.finish()
+    }
+}
+
+/// Writes a trace with a file header
+/// the record count and checksum are filled in by `finish`
+pub struct TraceWriter<W: Write + Seek> {
+    out: W,
+    header: TraceHeader,
+    /// Position of the header within `out`
+    start: u64,
+    checksum: Checksum,
+    encoder: RecordEncoder,
+    buf: Vec<u8>,
+}
+
+impl<W: Write + Seek> TraceWriter<W> {
+    pub fn new(out: W, producer: impl Into<String>) -> io::Result<Self> {
+        Self::with_encoding(out, producer, TraceEncoding::Fixed)
+    }
+
+    pub fn with_encoding(
+        out: W,
+        producer: impl Into<String>,
+        encoding: TraceEncoding,
+    ) -> io::Result<Self> {
+        let format = TraceFormat {
+            encoding,
+            ..Default::default()
+        };
+        Self::with_format(out, producer, format)
+    }
+
+    pub fn with_format(
+        mut out: W,
+        producer: impl Into<String>,
+        format: TraceFormat,
+    ) -> io::Result<Self> {
+        let mut header = TraceHeader::new(producer);
+        header.flags = format.flags();
+        let start = out.stream_position()?;
+        out.write_all(&header.to_bytes())?;
+
+        Ok(Self {
+            out,
+            header,
+            start,
+            checksum: Checksum::default(),
+            encoder: RecordEncoder::new(format),
+            buf: Vec::with_capacity(RECORD_HEADER_LEN + 8),
+        })
+    }
+
+    /// Append one record
+    /// with `load_values` set, a load's `value` must hold the value it returned
+    pub fn write_op(&mut self, op: &MemOp) -> io::Result<()> {
+        self.buf.clear();
+        self.encoder.encode(op, &mut self.buf);
+        self.checksum.update(&self.buf);
+        self.header.record_count += 1;
+        self.out.write_all(&self.buf)
+    }
+
+    /// Patch the header with the final record count and checksum
+    /// returns the output and the final header
+    pub fn finish(mut self) -> io::Result<(W, TraceHeader)> {
+        self.header.checksum = self.checksum.finish();
+
+        let end = self.out.stream_position()?;
+        self.out.seek(SeekFrom::Start(self.start))?;
+        self.out.write_all(&self.header.to_bytes())?;
+        self.out.seek(SeekFrom::Start(end))?;
+        self.out.flush()?;
+        Ok((self.out, self.header))
+    }
+}
+
+/// Encodes records one at a time, tracking the previous address for delta encoding
+pub(crate) struct RecordEncoder {
+    format: TraceFormat,
+    prev_addr: u64,
+}
+
+impl RecordEncoder {
+    pub(crate) fn new(format: TraceFormat) -> Self {
+        Self {
+            format,
+            prev_addr: 0,
+        }
+    }
+
+    pub(crate) fn encode(&mut self, op: &MemOp, out: &mut Vec<u8>) {
+        let width = op.width.bytes();
+
+        match self.format.encoding {
+            TraceEncoding::Fixed => {
+                let code = match op.kind {
+                    OpKind::Store => OP_STORE,
+                    OpKind::Load => OP_LOAD,
+                };
+                out.push(code);
+                out.push(width as u8);
+                out.extend_from_slice(&op.addr.to_le_bytes());
+            }
+            TraceEncoding::Delta => {
+                out.push(encode_tag(op.kind, op.width));
+                let delta = op.addr.wrapping_sub(self.prev_addr) as i64;
+                write_varint(zigzag_encode(delta), out);
+                self.prev_addr = op.addr;
+            }
+        }
+
+        if op.kind == OpKind::Store || self.format.load_values {
+            out.extend_from_slice(&op.value.to_le_bytes()[..width]);
+        }
+    }
+}
+
+#[inline]
+fn encode_tag(kind: OpKind, width: Width) -> u8 {
+    let store = match kind {
+        OpKind::Store => TAG_STORE,
+        OpKind::Load => 0,
+    };
+    store | ((width.bytes().trailing_zeros() as u8) << 1)
+}
+
+/// Split a delta encoding tag into kind and width
+/// `None` if any reserved bit is set
+#[inline]
+pub(crate) fn decode_tag(tag: u8) -> Option<(OpKind, Width)> {
+    if tag >> 3 != 0 {
+        return None;
+    }
+
+    let kind = if tag & TAG_STORE != 0 {
+        OpKind::Store
+    } else {
+        OpKind::Load
+    };
+    let width = Width::from_bytes(1 << (tag >> 1))?;
+    Some((kind, width))
+}
+
+#[inline]
+fn zigzag_encode(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+#[inline]
+pub(crate) fn zigzag_decode(value: u64) -> i64 {
+    ((value >> 1) as i64) ^ -((value & 1) as i64)
+}
+
+fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push((value as u8) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reasons a varint can't be decoded
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum VarintError {
+    /// The input ended before the last byte of the varint
+    Truncated,
+    /// The varint doesn't fit in a u64
+    Overflow,
+}
+
+/// Decode a LEB128 varint, returning the value and its length in bytes
+#[inline]
+pub(crate) fn read_varint(bytes: &[u8]) -> Result<(u64, usize), VarintError> {
+    let mut value = 0u64;
+    for (i, &byte) in bytes.iter().take(MAX_VARINT_LEN).enumerate() {
+        let bits = (byte & 0x7f) as u64;
+        if i == MAX_VARINT_LEN - 1 && bits > 1 {
+            return Err(VarintError::Overflow);
+        }
+        value |= bits << (7 * i);
+        if byte & 0x80 == 0 {
+            return Ok((value, i + 1));
+        }
+    }
+
+    if bytes.len() >= MAX_VARINT_LEN {
+        Err(VarintError::Overflow)
+    } else {
+        Err(VarintError::Truncated)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::trace_format::{
+        Checksum, HeaderError, TraceHeader, VarintError, read_varint, write_varint, zigzag_decode,
+        zigzag_encode,
+    };
+
+    #[test]
+    fn header_roundtrip() {
+        let mut header = TraceHeader::new("tracer");
+        header.record_count = 42;
+        header.checksum = 0xDEAD_BEEF;
+
+        let bytes = header.to_bytes();
+        assert!(TraceHeader::is_present(&bytes));
+        assert_eq!(TraceHeader::parse(&bytes), Ok((header, bytes.len())));
+    }
+
+    #[test]
+    fn rejects_future_versions() {
+        let mut bytes = TraceHeader::new("tracer").to_bytes();
+        bytes[8] = 9;
+        assert_eq!(
+            TraceHeader::parse(&bytes),
+            Err(HeaderError::UnsupportedVersion(9))
+        );
+        assert_eq!(
+            HeaderError::UnsupportedVersion(9).to_string(),
+            "unsupported trace version 9"
+        );
+    }
+
+    #[test]
+    fn checksum_is_independent_of_chunking() {
+        let data: Vec<u8> = (0..100u8).collect();
+
+        let mut split = Checksum::default();
+        for chunk in data.chunks(3) {
+            split.update(chunk);
+        }
+
+        assert_eq!(split.finish(), Checksum::of(&data));
+        assert_ne!(Checksum::of(&data[..99]), Checksum::of(&data));
+    }
+
+    #[test]
+    fn varint_zigzag_roundtrip() {
+        for value in [0, 1, -1, 63, -64, 4096, -4096, i64::MAX, i64::MIN] {
+            let mut buf = vec![];
+            write_varint(zigzag_encode(value), &mut buf);
+            let (decoded, len) = read_varint(&buf).unwrap();
+            assert_eq!(len, buf.len());
+            assert_eq!(zigzag_decode(decoded), value);
+        }
+
+        assert_eq!(read_varint(&[0x80, 0x80]), Err(VarintError::Truncated));
+        assert_eq!(read_varint(&[0xff; 11]), Err(VarintError::Overflow));
+    }
+}
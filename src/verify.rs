@@ -0,0 +1,206 @@
+use std::{fmt, path::Path};
+
+use crate::{
+    MemoryEmulator, ReplayStats,
+    replay_reader::{MemOp, ReplayError, ReplayReader, Width, map_trace},
+    rewrite_trace,
+    trace_format::{TraceEncoding, TraceFormat, TraceHeader},
+};
+
+/// A load that returned something other than the value recorded in the trace
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LoadMismatch {
+    /// Zero based index of the load record
+    pub record: u64,
+    pub addr: u64,
+    pub width: Width,
+    pub expected: u64,
+    pub actual: u64,
+}
+
+#[derive(Debug)]
+pub enum VerifyError {
+    Replay(ReplayError),
+    /// The trace doesn't record load values, so there is nothing to verify against
+    NoLoadValues,
+    Mismatch(LoadMismatch),
+}
+
+impl From<ReplayError> for VerifyError {
+    fn from(err: ReplayError) -> Self {
+        VerifyError::Replay(err)
+    }
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            VerifyError::Replay(e) => write!(f, "{}", e),
+            VerifyError::NoLoadValues => write!(f, "trace does not record load values"),
+            VerifyError::Mismatch(m) => write!(
+                f,
+                "load mismatch at record {}: {}-byte load from 0x{:x} expected 0x{:x}, got 0x{:x}",
+                m.record,
+                m.width.bytes(),
+                m.addr,
+                m.expected,
+                m.actual
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VerifyError {}
+
+/// Replay a trace with load values, checking every load against the recorded value
+/// stops at the first mismatch
+pub fn replay_and_verify<P: AsRef<Path>, M: MemoryEmulator>(
+    file_path: P,
+    mem_emulator: &mut M,
+) -> Result<ReplayStats, VerifyError> {
+    let mmap = map_trace(file_path).map_err(ReplayError::io)?;
+    let mut reader = ReplayReader::from_slice(&mmap);
+    if !reader.header()?.is_some_and(TraceHeader::has_load_values) {
+        return Err(VerifyError::NoLoadValues);
+    }
+
+    let mut stats = ReplayStats::default();
+
+    for op in reader {
+        let op = op?;
+        if let Some(actual) = op.apply(mem_emulator)
+            && actual != op.value
+        {
+            return Err(VerifyError::Mismatch(LoadMismatch {
+                record: stats.records,
+                addr: op.addr,
+                width: op.width,
+                expected: op.value,
+                actual,
+            }));
+        }
+        stats.record(op.kind);
+    }
+
+    Ok(stats)
+}
+
+/// Write a copy of a trace whose loads record the values returned by `reference`
+/// the result can then be checked against other emulators with `replay_and_verify`
+pub fn record_load_values<P: AsRef<Path>, Q: AsRef<Path>, M: MemoryEmulator>(
+    input_path: P,
+    output_path: Q,
+    encoding: TraceEncoding,
+    reference: &mut M,
+) -> Result<TraceHeader, ReplayError> {
+    let format = TraceFormat {
+        encoding,
+        load_values: true,
+    };
+
+    rewrite_trace(
+        input_path,
+        output_path,
+        |_| format,
+        |op| match op.apply(reference) {
+            Some(value) => MemOp { value, ..op },
+            None => op,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use crate::{
+        emulators::paged::PagedMemoryFxHash,
+        replay_reader::{MemOp, OpKind, Width},
+        test_util::FaultyMemory,
+        trace_format::{TraceEncoding, TraceFormat, TraceWriter},
+        verify::{VerifyError, record_load_values, replay_and_verify},
+    };
+
+    #[test]
+    fn reports_first_mismatching_load() {
+        let path = std::env::temp_dir().join(format!("fast-mem-{}-verify.bin", std::process::id()));
+
+        let format = TraceFormat {
+            load_values: true,
+            ..Default::default()
+        };
+        let mut writer = TraceWriter::with_format(Cursor::new(vec![]), "test", format).unwrap();
+        for i in 0..4u64 {
+            let addr = 0x2000 + i * 4;
+            let store = MemOp {
+                kind: OpKind::Store,
+                width: Width::U32,
+                addr,
+                value: i + 10,
+            };
+            writer.write_op(&store).unwrap();
+            writer
+                .write_op(&MemOp {
+                    kind: OpKind::Load,
+                    ..store
+                })
+                .unwrap();
+        }
+        std::fs::write(&path, writer.finish().unwrap().0.into_inner()).unwrap();
+
+        let stats = replay_and_verify(&path, &mut PagedMemoryFxHash::default()).unwrap();
+        assert_eq!(stats.loads, 4);
+
+        match replay_and_verify(&path, &mut FaultyMemory::corrupt_loads(0x2004..0x2005)) {
+            Err(VerifyError::Mismatch(m)) => {
+                assert_eq!((m.record, m.addr, m.width), (3, 0x2004, Width::U32));
+                assert_eq!((m.expected, m.actual), (11, 10));
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn recorded_load_values_verify() {
+        let dir = std::env::temp_dir();
+        let legacy = dir.join(format!("fast-mem-{}-unrecorded.bin", std::process::id()));
+        let recorded = dir.join(format!("fast-mem-{}-recorded.bin", std::process::id()));
+
+        let mut trace = vec![];
+        for i in 0..4u64 {
+            let addr = 0x2000 + i * 4;
+            trace.extend_from_slice(&[1, 4]);
+            trace.extend_from_slice(&addr.to_le_bytes());
+            trace.extend_from_slice(&(i as u32 + 10).to_le_bytes());
+            trace.extend_from_slice(&[2, 4]);
+            trace.extend_from_slice(&addr.to_le_bytes());
+        }
+        std::fs::write(&legacy, &trace).unwrap();
+
+        let header = record_load_values(
+            &legacy,
+            &recorded,
+            TraceEncoding::Delta,
+            &mut PagedMemoryFxHash::default(),
+        )
+        .unwrap();
+        assert!(header.has_load_values());
+        assert_eq!(header.record_count, 8);
+
+        let stats = replay_and_verify(&recorded, &mut PagedMemoryFxHash::default()).unwrap();
+        assert_eq!(stats.loads, 4);
+
+        match replay_and_verify(&recorded, &mut FaultyMemory::corrupt_loads(0x2004..0x2005)) {
+            Err(VerifyError::Mismatch(m)) => {
+                assert_eq!((m.record, m.addr), (3, 0x2004));
+                assert_eq!((m.expected, m.actual), (11, 10));
+            }
+            other => panic!("expected a mismatch, got {:?}", other),
+        }
+
+        std::fs::remove_file(legacy).unwrap();
+        std::fs::remove_file(recorded).unwrap();
+    }
+}